@@ -0,0 +1,215 @@
+//! Firmware updates pushed over BLE, for devices that can't reach the update server.
+//!
+//! The app writes a [`DfuCommand`] to [`CONTROL_CHARACTERISTIC`], split up with
+//! [`crate::chunk`], and streams the image to [`DATA_CHARACTERISTIC`] in writes without
+//! response of at most [`MAX_DATA_LEN`] bytes. The device notifies a [`DfuStatus`] on the
+//! control characteristic whenever it wrote another [`WINDOW_LEN`] bytes to flash, and the
+//! app never gets more than a window ahead of that, so the flash keeps up with the radio.
+//!
+//! `Start` carries the release key's signature of the image hash, the same one the server
+//! publishes, and the device refuses the update unless it checks out.
+//!
+//! ```text
+//! app                                   device
+//!  |-- Start { size, sha256, signature }->|
+//!  |<---------------------- Received(0) --|
+//!  |== WINDOW_LEN bytes of data =========>|
+//!  |<------------------- Received(4096) --|
+//!  |   ...                                |
+//!  |-- Finish --------------------------->|
+//!  |<------------------------ Installed --|  and restarts into the new image
+//! ```
+//!
+//! Any [`DfuStatus::Failed`] ends the update, it has to start over.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub const SERVICE_UUID: [u8; 2] = [0xbe, 0xdf];
+pub const CONTROL_CHARACTERISTIC: [u8; 2] = [0xbe, 0xde];
+pub const DATA_CHARACTERISTIC: [u8; 2] = [0xbe, 0xdd];
+
+/// Largest data write, so every write fits the default 23 byte ATT MTU.
+pub const MAX_DATA_LEN: usize = 20;
+/// Bytes the app may send before waiting for the device to acknowledge them.
+pub const WINDOW_LEN: u32 = 4096;
+
+/// The image header, the first segment's header, then the app description.
+const APP_DESC_OFFSET: usize = 24 + 8;
+const IMAGE_MAGIC: u8 = 0xe9;
+const APP_DESC_MAGIC: u32 = 0xabcd_5432;
+/// The version follows the app description's magic word, secure version and two reserved words.
+const VERSION_OFFSET: usize = APP_DESC_OFFSET + 4 * 4;
+const VERSION_LEN: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DfuCommand {
+    /// Starts an update to an image of `size` bytes, abandoning any earlier one.
+    Start {
+        size: u32,
+        sha256: [u8; 32],
+        /// The release key's Ed25519 signature of `sha256`.
+        signature: Signature,
+    },
+    /// Installs the image once all of it arrived, the device restarts into it.
+    Finish,
+    /// Abandons the update.
+    Abort,
+}
+
+impl DfuCommand {
+    /// Upper bound on the encoded size: a one byte enum tag, a `u32` taking at most five
+    /// bytes, the hash and the signature.
+    pub const MAX_ENCODED_LEN: usize = 1 + 5 + 32 + 64;
+
+    pub fn encode<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], postcard::Error> {
+        postcard::to_slice(self, buf)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, postcard::Error> {
+        postcard::from_bytes(bytes)
+    }
+}
+
+/// An Ed25519 signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature(pub [u8; 64]);
+
+// serde only implements arrays of up to 32 elements, so it goes over in two halves
+impl Serialize for Signature {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (first, second) = self.0.split_at(32);
+        // both halves are exactly 32 bytes
+        let halves: (&[u8; 32], &[u8; 32]) =
+            (first.try_into().unwrap(), second.try_into().unwrap());
+        halves.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Signature {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (first, second) = <([u8; 32], [u8; 32])>::deserialize(deserializer)?;
+        let mut signature = [0; 64];
+        signature[..32].copy_from_slice(&first);
+        signature[32..].copy_from_slice(&second);
+        Ok(Signature(signature))
+    }
+}
+
+/// How the update is going, notified on [`CONTROL_CHARACTERISTIC`] as a single write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DfuStatus {
+    /// This many bytes of the image are written to flash.
+    Received(u32),
+    /// The image checked out and boots next, the device is about to restart.
+    Installed,
+    Failed(DfuError),
+}
+
+impl DfuStatus {
+    /// Upper bound on the encoded size: two one byte enum tags, or one and a `u32`.
+    pub const MAX_ENCODED_LEN: usize = 1 + 5;
+
+    pub fn encode<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], postcard::Error> {
+        postcard::to_slice(self, buf)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, postcard::Error> {
+        postcard::from_bytes(bytes)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DfuError {
+    /// Data or `Finish` arrived without a `Start`.
+    NotStarted,
+    /// The image doesn't fit in the device's app slot, or more data arrived than announced.
+    TooLarge,
+    /// `Finish` arrived before all of the image did.
+    Incomplete,
+    /// The image doesn't hash to what `Start` announced.
+    HashMismatch,
+    /// Writing the image to flash failed.
+    Flash,
+    /// The device is already updating from the server.
+    Busy,
+    /// The image hash isn't signed with the device's release key, or it was built without one.
+    BadSignature,
+}
+
+/// The version an ESP-IDF app image was built as, `None` if `image` isn't one.
+///
+/// The version comes from the app description the bootloader reads, which our firmware
+/// fills in from its `Cargo.toml`.
+pub fn image_version(image: &[u8]) -> Option<&str> {
+    if image.first() != Some(&IMAGE_MAGIC) {
+        return None;
+    }
+    let magic = image.get(APP_DESC_OFFSET..APP_DESC_OFFSET + 4)?;
+    if u32::from_le_bytes(magic.try_into().ok()?) != APP_DESC_MAGIC {
+        return None;
+    }
+
+    let field = image.get(VERSION_OFFSET..VERSION_OFFSET + VERSION_LEN)?;
+    let len = field.iter().position(|&b| b == 0).unwrap_or(VERSION_LEN);
+    core::str::from_utf8(&field[..len]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(version: &str) -> [u8; 256] {
+        let mut image = [0u8; 256];
+        image[0] = IMAGE_MAGIC;
+        image[APP_DESC_OFFSET..APP_DESC_OFFSET + 4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        image[VERSION_OFFSET..VERSION_OFFSET + version.len()].copy_from_slice(version.as_bytes());
+        image
+    }
+
+    #[test]
+    fn command_round_trip_fits_max_len() {
+        let command = DfuCommand::Start {
+            size: u32::MAX,
+            sha256: [0xff; 32],
+            signature: Signature(core::array::from_fn(|i| i as u8)),
+        };
+
+        let mut buf = [0u8; DfuCommand::MAX_ENCODED_LEN];
+        let encoded = command.encode(&mut buf).unwrap();
+
+        assert_eq!(DfuCommand::decode(encoded).unwrap(), command);
+    }
+
+    #[test]
+    fn status_round_trip_fits_max_len() {
+        for status in [
+            DfuStatus::Received(u32::MAX),
+            DfuStatus::Installed,
+            DfuStatus::Failed(DfuError::Busy),
+        ] {
+            let mut buf = [0u8; DfuStatus::MAX_ENCODED_LEN];
+            let encoded = status.encode(&mut buf).unwrap();
+
+            assert_eq!(DfuStatus::decode(encoded).unwrap(), status);
+        }
+    }
+
+    #[test]
+    fn reads_image_version() {
+        assert_eq!(image_version(&image("0.2.0")), Some("0.2.0"));
+        assert_eq!(
+            image_version(&image(&"9".repeat(32))),
+            Some("9".repeat(32).as_str())
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut not_an_app = image("0.2.0");
+        not_an_app[APP_DESC_OFFSET] = 0;
+
+        assert_eq!(image_version(&not_an_app), None);
+        assert_eq!(image_version(&image("0.2.0")[1..]), None);
+        assert_eq!(image_version(&[IMAGE_MAGIC]), None);
+    }
+}
@@ -4,26 +4,239 @@ use heapless::String;
 use serde::{Deserialize, Serialize};
 use uuid::{Uuid, uuid};
 
+pub mod chunk;
+pub mod dfu;
+pub mod record;
+
 // pub const CONFIG_SERVICE_UUID: Uuid = uuid!("171f7d49-bd79-4e85-9bbd-9e0c57191e56");
 // pub const CONFIG_SERVICE_UUID_STR: &str = "171f7d49-bd79-4e85-9bbd-9e0c57191e56";
 pub const CONFIG_SERVICE_UUID: [u8; 2] = [0xbe, 0xef];
-pub const WIFI_SSID_CHARACTERISTIC: [u8; 2] = [0xbe, 0xed];
-pub const WIFI_PASSWORD_CHARACTERISTIC: [u8; 2] = [0xbe, 0xee];
-pub const GITHUB_TOKEN_CHARACTERISTIC: [u8; 2] = [0xbe, 0xea];
+pub const CONTROL_CHARACTERISTIC: [u8; 2] = [0xbe, 0xec];
+pub const STATUS_CHARACTERISTIC: [u8; 2] = [0xbe, 0xeb];
+// 0xbeed, 0xbeee and 0xbeea were the old provisioning characteristics, older apps still
+// write to them, so they are never reused
+pub const SCAN_CHARACTERISTIC: [u8; 2] = [0xbe, 0xe9];
 
 pub const PERIPHERAL_NAME: &str = "CapyCoder";
 pub const PERIPHERAL_ADVERTISEMENT: &str = PERIPHERAL_NAME;
 
 // pub const WIFI_CREDENTIAL_CHARACTERISTIC: Uuid = uuid!("ab2f0d66-306f-4735-9af3-35930eeb31ca");
 pub const TOKENS_CHARACTERISTIC: Uuid = uuid!("361c1911-a3b1-4935-ae72-2ffc828099a1");
+/// `TOKENS_CHARACTERISTIC` in the little-endian byte order used on the air (and by trouble).
+pub const TOKENS_CHARACTERISTIC_LE: [u8; 16] = TOKENS_CHARACTERISTIC.as_u128().to_le_bytes();
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct WifiCredentials {
-    pub ssid: String<30>,
-    pub password: String<30>,
+    /// SSIDs are at most 32 bytes.
+    pub ssid: String<32>,
+    /// WPA passphrases are at most 63 characters, or 64 hex digits for a raw PSK.
+    pub password: String<64>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Tokens {
-    pub github: String<30>,
+    /// Roomy enough for fine-grained personal access tokens (~93 characters).
+    pub github: String<100>,
+    /// Sent to the server as a bearer token, for self-hosted servers behind auth.
+    pub server: String<64>,
+}
+
+/// Everything the app sends to provision a device, written to `TOKENS_CHARACTERISTIC`
+/// as a single postcard message split up with [`chunk`].
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provisioning {
+    pub wifi_credentials: WifiCredentials,
+    pub tokens: Tokens,
+    /// Shown on the device, left as is when empty.
+    pub device_name: String<30>,
+    /// Whose pull requests, workflow runs and commits to show, left as is when empty.
+    pub github_user: String<39>,
+    /// Seconds between metrics refreshes, left as is when zero.
+    pub refresh_secs: u16,
+    /// Base URL of the CappyCoding server, like `https://capy.example.com`,
+    /// left as is when empty.
+    pub server_url: String<96>,
+    /// Fast e-paper updates between full refreshes that clear the ghosting,
+    /// left as is when zero.
+    pub full_refresh_every: u8,
+}
+
+impl Provisioning {
+    /// Upper bound on the encoded size, every string is a one byte length plus its contents
+    /// a `u16` takes at most three bytes and a `u8` one.
+    pub const MAX_ENCODED_LEN: usize =
+        (1 + 32) + (1 + 64) + (1 + 100) + (1 + 64) + (1 + 30) + (1 + 39) + 3 + (1 + 96) + 1;
+
+    pub fn encode<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], postcard::Error> {
+        postcard::to_slice(self, buf)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, postcard::Error> {
+        postcard::from_bytes(bytes)
+    }
+}
+
+/// Security of a scanned network, as reported by the radio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthMethod {
+    Open,
+    Wep,
+    Wpa,
+    Wpa2Personal,
+    WpaWpa2Personal,
+    Wpa2Enterprise,
+    Wpa3Personal,
+    Wpa2Wpa3Personal,
+    WapiPersonal,
+    /// The radio didn't say.
+    Unknown,
+}
+
+/// One network seen by the device.
+///
+/// Writing anything to `SCAN_CHARACTERISTIC` makes the device scan and notify every
+/// network it found as its own postcard message, split up with [`chunk`].
+/// An empty message marks the end of the scan.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScannedNetwork {
+    pub ssid: String<32>,
+    /// Signal strength in dBm.
+    pub rssi: i8,
+    pub channel: u8,
+    pub auth: AuthMethod,
+}
+
+impl ScannedNetwork {
+    /// Upper bound on the encoded size: the ssid, one byte each for rssi and channel,
+    /// and a one byte enum tag.
+    pub const MAX_ENCODED_LEN: usize = (1 + 32) + 1 + 1 + 1;
+
+    pub fn encode<'a>(&self, buf: &'a mut [u8]) -> Result<&'a mut [u8], postcard::Error> {
+        postcard::to_slice(self, buf)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, postcard::Error> {
+        postcard::from_bytes(bytes)
+    }
+}
+
+/// Single byte commands accepted by the `CONTROL_CHARACTERISTIC`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ControlOpcode {
+    /// Wipe the stored config and go back to being unprovisioned.
+    FactoryReset = 0x01,
+    /// Does nothing, but like every control write it needs an authenticated link,
+    /// so the app sends it first to get pairing out of the way.
+    Pair = 0x02,
+    /// Keeps a freshly updated firmware, which otherwise rolls back unless it reaches the
+    /// server. The app sends it once the device came back with the version it pushed.
+    ConfirmFirmware = 0x03,
+}
+
+impl TryFrom<u8> for ControlOpcode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x01 => Ok(ControlOpcode::FactoryReset),
+            0x02 => Ok(ControlOpcode::Pair),
+            0x03 => Ok(ControlOpcode::ConfirmFirmware),
+            other => Err(other),
+        }
+    }
+}
+
+/// How far the device got joining Wi-Fi, a single byte notified on `STATUS_CHARACTERISTIC`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProvisioningStatus {
+    /// No Wi-Fi credentials stored yet.
+    Unprovisioned = 0x00,
+    Connecting = 0x01,
+    /// Joined the network and got an address from DHCP.
+    GotIp = 0x02,
+    /// The network is around but wouldn't let us in, most likely a wrong password.
+    AuthFailed = 0x03,
+    /// The network didn't show up in a scan.
+    ApNotFound = 0x04,
+    /// The CappyCoding server answered.
+    ServerReachable = 0x05,
+    /// Something answered for the server, but its certificate didn't verify.
+    ServerUntrusted = 0x06,
+}
+
+impl ProvisioningStatus {
+    /// Whether joining failed in a way that new credentials are needed to fix.
+    pub fn is_failure(self) -> bool {
+        matches!(
+            self,
+            ProvisioningStatus::AuthFailed | ProvisioningStatus::ApNotFound
+        )
+    }
+}
+
+impl TryFrom<u8> for ProvisioningStatus {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x00 => Ok(ProvisioningStatus::Unprovisioned),
+            0x01 => Ok(ProvisioningStatus::Connecting),
+            0x02 => Ok(ProvisioningStatus::GotIp),
+            0x03 => Ok(ProvisioningStatus::AuthFailed),
+            0x04 => Ok(ProvisioningStatus::ApNotFound),
+            0x05 => Ok(ProvisioningStatus::ServerReachable),
+            0x06 => Ok(ProvisioningStatus::ServerUntrusted),
+            other => Err(other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn provisioning_round_trip_fits_max_len() {
+        fn full<const N: usize>() -> String<N> {
+            String::try_from("x".repeat(N).as_str()).unwrap()
+        }
+
+        let provisioning = Provisioning {
+            wifi_credentials: WifiCredentials {
+                ssid: full(),
+                password: full(),
+            },
+            tokens: Tokens {
+                github: full(),
+                server: full(),
+            },
+            device_name: full(),
+            github_user: full(),
+            refresh_secs: u16::MAX,
+            server_url: full(),
+            full_refresh_every: u8::MAX,
+        };
+
+        let mut buf = [0u8; Provisioning::MAX_ENCODED_LEN];
+        let encoded = provisioning.encode(&mut buf).unwrap();
+
+        assert_eq!(Provisioning::decode(encoded).unwrap(), provisioning);
+    }
+
+    #[test]
+    fn scanned_network_round_trip_fits_max_len() {
+        let network = ScannedNetwork {
+            ssid: String::try_from("y".repeat(32).as_str()).unwrap(),
+            rssi: -90,
+            channel: 13,
+            auth: AuthMethod::Unknown,
+        };
+
+        let mut buf = [0u8; ScannedNetwork::MAX_ENCODED_LEN];
+        let encoded = network.encode(&mut buf).unwrap();
+
+        assert_eq!(ScannedNetwork::decode(encoded).unwrap(), network);
+    }
 }
@@ -0,0 +1,337 @@
+//! On-flash record format for persisted config.
+//!
+//! Every record is a fixed little-endian header followed by a postcard payload:
+//!
+//! | offset | size | field                              |
+//! |--------|------|------------------------------------|
+//! | 0      | 4    | magic (`b"CAPY"`)                  |
+//! | 4      | 2    | schema version of the payload      |
+//! | 6      | 2    | payload length                     |
+//! | 8      | 4    | sequence number                    |
+//! | 12     | 4    | CRC32 (ISO-HDLC), see below        |
+//! | 16     | n    | postcard encoded payload           |
+//!
+//! The CRC covers the version, length and sequence fields as well as the payload,
+//! so a record that was only partially written is never mistaken for a valid one.
+//!
+//! Records are meant to be written to alternating slots, each with a higher
+//! sequence number than the last, so the newest valid slot wins on load.
+
+use crc::{CRC_32_ISO_HDLC, Crc};
+use serde::{Serialize, de::DeserializeOwned};
+
+pub const RECORD_MAGIC: [u8; 4] = *b"CAPY";
+pub const HEADER_LEN: usize = 16;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordError {
+    /// The buffer is erased flash, nothing was ever written.
+    Empty,
+    /// The buffer doesn't start with [`RECORD_MAGIC`].
+    BadMagic,
+    /// The header claims more payload than the buffer holds.
+    BadLength,
+    /// The payload doesn't match its checksum.
+    BadCrc,
+    /// The value doesn't fit in the buffer.
+    BufferTooSmall,
+    /// The payload passed the checksum but couldn't be decoded.
+    Deserialize,
+    /// The record was written by a schema version we can't read.
+    UnsupportedVersion(u16),
+}
+
+/// A type that can be stored in a record.
+///
+/// Bump `VERSION` whenever the serialized layout changes, and teach `migrate`
+/// how to read the older layouts so devices in the field keep their config.
+pub trait Versioned: Serialize + DeserializeOwned {
+    const VERSION: u16;
+
+    /// Decodes a payload written with an older (or newer) schema `version`.
+    fn migrate(version: u16, _payload: &[u8]) -> Result<Self, RecordError> {
+        Err(RecordError::UnsupportedVersion(version))
+    }
+}
+
+/// Header of a record, without the payload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordHeader {
+    pub version: u16,
+    pub len: u16,
+    pub sequence: u32,
+    pub crc: u32,
+}
+
+impl RecordHeader {
+    /// Whether this record was written after `other`, tolerating sequence wrap around.
+    pub fn is_newer_than(&self, other: &RecordHeader) -> bool {
+        (self.sequence.wrapping_sub(other.sequence) as i32) > 0
+    }
+
+    /// Parses and validates the header at the start of `buf`, returning it along with the payload.
+    pub fn parse(buf: &[u8]) -> Result<(Self, &[u8]), RecordError> {
+        if buf.len() < HEADER_LEN {
+            return Err(RecordError::BadLength);
+        }
+
+        let (header, rest) = buf.split_at(HEADER_LEN);
+
+        if header.iter().all(|&b| b == 0xFF) {
+            return Err(RecordError::Empty);
+        }
+
+        if header[0..4] != RECORD_MAGIC {
+            return Err(RecordError::BadMagic);
+        }
+
+        let header = Self {
+            version: u16::from_le_bytes([header[4], header[5]]),
+            len: u16::from_le_bytes([header[6], header[7]]),
+            sequence: u32::from_le_bytes([header[8], header[9], header[10], header[11]]),
+            crc: u32::from_le_bytes([header[12], header[13], header[14], header[15]]),
+        };
+
+        let payload = rest
+            .get(..header.len as usize)
+            .ok_or(RecordError::BadLength)?;
+
+        if header.checksum(payload) != header.crc {
+            return Err(RecordError::BadCrc);
+        }
+
+        Ok((header, payload))
+    }
+
+    fn checksum(&self, payload: &[u8]) -> u32 {
+        let mut digest = CRC32.digest();
+        digest.update(&self.version.to_le_bytes());
+        digest.update(&self.len.to_le_bytes());
+        digest.update(&self.sequence.to_le_bytes());
+        digest.update(payload);
+        digest.finalize()
+    }
+
+    fn write(&self, buf: &mut [u8]) {
+        buf[0..4].copy_from_slice(&RECORD_MAGIC);
+        buf[4..6].copy_from_slice(&self.version.to_le_bytes());
+        buf[6..8].copy_from_slice(&self.len.to_le_bytes());
+        buf[8..12].copy_from_slice(&self.sequence.to_le_bytes());
+        buf[12..16].copy_from_slice(&self.crc.to_le_bytes());
+    }
+}
+
+/// Whether `buf` is erased or zeroed flash, which holds no config of any format.
+///
+/// Zeroes are also a valid postcard encoding of an empty value, check this before
+/// decoding anything that predates records.
+pub fn is_blank(buf: &[u8]) -> bool {
+    buf.iter().all(|&b| b == 0xFF) || buf.iter().all(|&b| b == 0x00)
+}
+
+/// Encodes `value` as a record into `buf`, returning the used part of the buffer.
+pub fn encode<'a, T: Versioned>(
+    value: &T,
+    sequence: u32,
+    buf: &'a mut [u8],
+) -> Result<&'a [u8], RecordError> {
+    if buf.len() < HEADER_LEN {
+        return Err(RecordError::BufferTooSmall);
+    }
+
+    let (header, rest) = buf.split_at_mut(HEADER_LEN);
+    let payload = postcard::to_slice(value, rest).map_err(|_| RecordError::BufferTooSmall)?;
+    let len = u16::try_from(payload.len()).map_err(|_| RecordError::BufferTooSmall)?;
+
+    let mut record_header = RecordHeader {
+        version: T::VERSION,
+        len,
+        sequence,
+        crc: 0,
+    };
+    record_header.crc = record_header.checksum(payload);
+    record_header.write(header);
+
+    Ok(&buf[..HEADER_LEN + len as usize])
+}
+
+/// Decodes a record from the start of `buf`, migrating older schema versions.
+pub fn decode<T: Versioned>(buf: &[u8]) -> Result<(RecordHeader, T), RecordError> {
+    let (header, payload) = RecordHeader::parse(buf)?;
+
+    let value = if header.version != T::VERSION {
+        T::migrate(header.version, payload)?
+    } else {
+        postcard::from_bytes(payload).map_err(|_| RecordError::Deserialize)?
+    };
+
+    Ok((header, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Tokens, WifiCredentials};
+    use serde::Deserialize;
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    struct TestConfig {
+        wifi: WifiCredentials,
+        tokens: Tokens,
+    }
+
+    impl Versioned for TestConfig {
+        const VERSION: u16 = 2;
+
+        fn migrate(version: u16, payload: &[u8]) -> Result<Self, RecordError> {
+            match version {
+                // version 1 only stored wifi credentials
+                1 => Ok(Self {
+                    wifi: postcard::from_bytes(payload).map_err(|_| RecordError::Deserialize)?,
+                    tokens: Tokens::default(),
+                }),
+                v => Err(RecordError::UnsupportedVersion(v)),
+            }
+        }
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct V1Config(WifiCredentials);
+
+    impl Versioned for V1Config {
+        const VERSION: u16 = 1;
+    }
+
+    fn sample() -> TestConfig {
+        TestConfig {
+            wifi: WifiCredentials {
+                ssid: "capy net".try_into().unwrap(),
+                password: "hunter2".try_into().unwrap(),
+            },
+            tokens: Tokens {
+                github: "ghp_capybara".try_into().unwrap(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = [0xFF; 128];
+        let len = encode(&sample(), 7, &mut buf).unwrap().len();
+
+        assert_eq!(buf[..4], RECORD_MAGIC);
+        let (header, value) = decode::<TestConfig>(&buf[..len]).unwrap();
+        assert_eq!(header.sequence, 7);
+        assert_eq!(value, sample());
+        // trailing bytes after the payload are ignored
+        assert_eq!(decode::<TestConfig>(&buf).unwrap().1, sample());
+    }
+
+    #[test]
+    fn erased_flash_is_empty() {
+        assert_eq!(decode::<TestConfig>(&[0xFF; 64]), Err(RecordError::Empty));
+    }
+
+    #[test]
+    fn zeroed_flash_is_blank() {
+        assert!(is_blank(&[0x00; 64]));
+        assert!(is_blank(&[0xFF; 64]));
+        // zeroes decode as an empty legacy config, without the check they'd be loaded
+        let legacy: WifiCredentials = postcard::from_bytes(&[0x00; 64]).unwrap();
+        assert!(legacy.ssid.is_empty());
+
+        let mut buf = [0xFF; 128];
+        encode(&sample(), 7, &mut buf).unwrap();
+        assert!(!is_blank(&buf));
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert_eq!(
+            decode::<TestConfig>(&[0x00; 64]),
+            Err(RecordError::BadMagic)
+        );
+        assert_eq!(
+            decode::<TestConfig>(&RECORD_MAGIC),
+            Err(RecordError::BadLength)
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_payload() {
+        let mut buf = [0xFF; 128];
+        let len = encode(&sample(), 7, &mut buf).unwrap().len();
+        buf[len - 1] ^= 0x01;
+
+        assert_eq!(decode::<TestConfig>(&buf), Err(RecordError::BadCrc));
+    }
+
+    #[test]
+    fn rejects_partially_written_header() {
+        let mut buf = [0xFF; 128];
+        encode(&sample(), 7, &mut buf).unwrap();
+        // the sequence number never made it to flash
+        buf[8..12].fill(0xFF);
+
+        assert_eq!(decode::<TestConfig>(&buf), Err(RecordError::BadCrc));
+    }
+
+    #[test]
+    fn rejects_truncated_payload() {
+        let mut buf = [0xFF; 128];
+        let len = encode(&sample(), 7, &mut buf).unwrap().len();
+
+        assert_eq!(
+            decode::<TestConfig>(&buf[..len - 1]),
+            Err(RecordError::BadLength)
+        );
+    }
+
+    #[test]
+    fn buffer_too_small() {
+        let mut buf = [0u8; HEADER_LEN + 4];
+        assert_eq!(
+            encode(&sample(), 7, &mut buf),
+            Err(RecordError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn newer_sequence_wins_across_wrap_around() {
+        let header = |sequence| RecordHeader {
+            version: 1,
+            len: 0,
+            sequence,
+            crc: 0,
+        };
+
+        assert!(header(2).is_newer_than(&header(1)));
+        assert!(!header(1).is_newer_than(&header(2)));
+        assert!(!header(1).is_newer_than(&header(1)));
+        assert!(header(0).is_newer_than(&header(u32::MAX)));
+    }
+
+    #[test]
+    fn migrates_old_versions() {
+        let mut buf = [0xFF; 128];
+        encode(&V1Config(sample().wifi), 7, &mut buf).unwrap();
+
+        let (_, migrated) = decode::<TestConfig>(&buf).unwrap();
+        assert_eq!(migrated.wifi, sample().wifi);
+        assert_eq!(migrated.tokens, Tokens::default());
+    }
+
+    #[test]
+    fn unknown_versions_are_refused() {
+        let mut buf = [0xFF; 128];
+        encode(&sample(), 7, &mut buf).unwrap();
+
+        assert_eq!(
+            decode::<V1Config>(&buf).map(|_| ()),
+            Err(RecordError::UnsupportedVersion(2))
+        );
+    }
+}
@@ -1,15 +1,43 @@
 use anyhow::anyhow;
+use anyhow::Context;
 use anyhow::Result;
-use ble_types::PERIPHERAL_NAME;
-use log::info;
+use ble_types::chunk::{chunks, Chunk, Reassembler, MAX_CHUNK_LEN};
+use ble_types::dfu::{self, DfuCommand, DfuStatus, MAX_DATA_LEN, WINDOW_LEN};
+use ble_types::{
+    AuthMethod, ControlOpcode, Provisioning, ProvisioningStatus, ScannedNetwork, Tokens,
+    WifiCredentials, CONFIG_SERVICE_UUID, CONTROL_CHARACTERISTIC, PERIPHERAL_NAME,
+    SCAN_CHARACTERISTIC, STATUS_CHARACTERISTIC, TOKENS_CHARACTERISTIC,
+};
+use futures::{Stream, StreamExt};
+use log::{info, warn};
+use sha2::{Digest, Sha256};
 
 use std::time::Duration;
 use tokio::time;
 
-use btleplug::api::{Central, Manager as _, Peripheral as PeripheralTrait, ScanFilter};
+use btleplug::api::bleuuid::uuid_from_u16;
+use btleplug::api::{
+    Central, Characteristic, Manager as _, Peripheral as PeripheralTrait, ScanFilter,
+    ValueNotification, WriteType,
+};
 
 use btleplug::platform::Manager;
 use btleplug::platform::Peripheral;
+use uuid::Uuid;
+
+/// How long the capycoder gets to join the network and reach the server after provisioning.
+const PROVISIONING_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the capycoder gets to finish a Wi-Fi scan.
+const SCAN_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long the capycoder gets to acknowledge a window of a firmware update.
+const DFU_WINDOW_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the capycoder gets to check and install a firmware update.
+const DFU_INSTALL_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the capycoder gets to restart into a new firmware and show up again.
+const DFU_RESTART_TIMEOUT: Duration = Duration::from_secs(60);
+/// The standard Device Information Service and its Firmware Revision String.
+const DEVICE_INFORMATION_SERVICE: u16 = 0x180a;
+const FIRMWARE_REVISION_CHARACTERISTIC: u16 = 0x2a26;
 
 #[derive(Default, Debug, Clone)]
 pub struct CapyCoder {
@@ -21,8 +49,19 @@ impl CapyCoder {
         let capycoder_perif = get_peripheral().await?;
 
         capycoder_perif.connect().await?;
+        // keep hold of it straight away, so `disconnect` works even if discovery fails
+        self.peripheral = Some(capycoder_perif.clone());
+
+        capycoder_perif.discover_services().await?;
 
-        self.peripheral = Some(capycoder_perif);
+        let service_uuid = short_uuid(CONFIG_SERVICE_UUID);
+        if !capycoder_perif
+            .services()
+            .iter()
+            .any(|s| s.uuid == service_uuid)
+        {
+            return Err(anyhow!("capycoder is missing the config service!"));
+        }
 
         info!("connection to capycoder successful!");
 
@@ -37,8 +76,430 @@ impl CapyCoder {
         Ok(perf.disconnect().await?)
     }
 
-    pub async fn send_config_data(&mut self) -> Result<()> {
-        todo!()
+    /// Connects, pairs and sends `provisioning` to the device, leaving the connection open.
+    ///
+    /// Then waits for the device to join the network, returning the last status it reported.
+    /// Errors if the device reports the network missing or the password wrong.
+    pub async fn provision(&mut self, provisioning: &Provisioning) -> Result<ProvisioningStatus> {
+        self.connect().await.context("failed to connect")?;
+        self.pair().await?;
+
+        // subscribe first, so no status sent in response to the write gets lost
+        let perf = self.peripheral()?;
+        let status = find_characteristic(perf, short_uuid(STATUS_CHARACTERISTIC))?;
+        perf.subscribe(&status).await?;
+        let mut notifications = perf.notifications().await?;
+
+        self.send_config_data(provisioning)
+            .await
+            .context("failed to send config")?;
+
+        let mut last = ProvisioningStatus::Connecting;
+        let wait = async {
+            while let Some(notification) = notifications.next().await {
+                if notification.uuid != status.uuid {
+                    continue;
+                }
+                let Some(&raw) = notification.value.first() else {
+                    continue;
+                };
+                let Ok(reported) = ProvisioningStatus::try_from(raw) else {
+                    warn!("capycoder reported unknown status {raw}");
+                    continue;
+                };
+
+                info!("capycoder status: {reported:?}");
+                last = reported;
+                if reported.is_failure()
+                    || reported == ProvisioningStatus::ServerReachable
+                    || reported == ProvisioningStatus::ServerUntrusted
+                {
+                    break;
+                }
+            }
+        };
+
+        if time::timeout(PROVISIONING_TIMEOUT, wait).await.is_err() {
+            warn!("gave up waiting for capycoder status, last was {last:?}");
+        }
+
+        match last {
+            ProvisioningStatus::AuthFailed => Err(anyhow!("wrong wifi password")),
+            ProvisioningStatus::ApNotFound => Err(anyhow!("wifi network not found")),
+            ProvisioningStatus::ServerUntrusted => Err(anyhow!(
+                "capycoder joined the wifi, but couldn't verify the server's certificate"
+            )),
+            ProvisioningStatus::Unprovisioned | ProvisioningStatus::Connecting => {
+                Err(anyhow!("capycoder didn't manage to join the wifi in time"))
+            }
+            status => Ok(status),
+        }
+    }
+
+    /// Connects and has the device scan for Wi-Fi networks, strongest first.
+    pub async fn scan_networks(&mut self) -> Result<Vec<ScannedNetwork>> {
+        self.connect().await.context("failed to connect")?;
+
+        let perf = self.peripheral()?;
+        let scan = find_characteristic(perf, short_uuid(SCAN_CHARACTERISTIC))?;
+        perf.subscribe(&scan).await?;
+        let mut notifications = perf.notifications().await?;
+
+        perf.write(&scan, &[0], WriteType::WithResponse).await?;
+
+        let mut networks = Vec::new();
+        let mut reassembler = Reassembler::<{ ScannedNetwork::MAX_ENCODED_LEN }>::new();
+        let collect = async {
+            while let Some(notification) = notifications.next().await {
+                if notification.uuid != scan.uuid {
+                    continue;
+                }
+
+                let message = match Chunk::parse(&notification.value)
+                    .and_then(|chunk| reassembler.push(chunk))
+                {
+                    Ok(Some(message)) => message,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        warn!("dropped scan chunk: {e:?}");
+                        continue;
+                    }
+                };
+
+                // an empty message ends the scan
+                if message.is_empty() {
+                    return Ok(());
+                }
+                match ScannedNetwork::decode(message) {
+                    Ok(network) => networks.push(network),
+                    Err(e) => warn!("invalid scan result: {e}"),
+                }
+            }
+            Err(anyhow!("capycoder went away during the scan"))
+        };
+
+        time::timeout(SCAN_TIMEOUT, collect)
+            .await
+            .map_err(|_| anyhow!("capycoder didn't finish scanning in time"))??;
+
+        info!("capycoder found {} networks", networks.len());
+        Ok(networks)
+    }
+
+    /// Makes sure the link is encrypted and authenticated before any secrets go over it.
+    ///
+    /// The capycoder refuses control writes on a link that wasn't paired with the passkey
+    /// shown on the device, which makes the OS run LE Secure Connections pairing (asking for
+    /// that passkey) and retry.
+    pub async fn pair(&mut self) -> Result<()> {
+        let perf = self.peripheral()?;
+        let characteristic = find_characteristic(perf, short_uuid(CONTROL_CHARACTERISTIC))?;
+
+        perf.write(
+            &characteristic,
+            &[ControlOpcode::Pair as u8],
+            WriteType::WithResponse,
+        )
+        .await
+        .map_err(|e| anyhow!("pairing with capycoder failed: {e}"))?;
+
+        info!("paired with capycoder");
+        Ok(())
+    }
+
+    /// Connects, pairs and streams the firmware `image` to the device, calling `progress`
+    /// with the percentage sent as it goes. The device only takes it with the release
+    /// key's `signature` of its hash.
+    ///
+    /// Then waits for the device to restart, checks it runs the image's version and confirms
+    /// it, so the device keeps it without reaching the server. The version is returned and
+    /// the connection closed afterwards.
+    pub async fn update_firmware(
+        &mut self,
+        image: &[u8],
+        signature: dfu::Signature,
+        mut progress: impl FnMut(u8),
+    ) -> Result<String> {
+        let version = dfu::image_version(image)
+            .ok_or(anyhow!("that's not a capycoder firmware image"))?
+            .to_string();
+        let size = u32::try_from(image.len()).context("firmware image is too large")?;
+
+        self.connect().await.context("failed to connect")?;
+        self.pair().await?;
+
+        let perf = self.peripheral()?;
+        let service = short_uuid(dfu::SERVICE_UUID);
+        let control =
+            find_service_characteristic(perf, service, short_uuid(dfu::CONTROL_CHARACTERISTIC))
+                .context("capycoder firmware doesn't support updates over bluetooth")?;
+        let data =
+            find_service_characteristic(perf, service, short_uuid(dfu::DATA_CHARACTERISTIC))?;
+        perf.subscribe(&control).await?;
+        let mut notifications = perf.notifications().await?;
+
+        let start = DfuCommand::Start {
+            size,
+            sha256: Sha256::digest(image).into(),
+            signature,
+        };
+        self.send_dfu_command(&control, &start).await?;
+        expect_received(&mut notifications, &control, 0).await?;
+        info!("capycoder accepted a {size} byte firmware update to {version}");
+
+        // send a window at a time, the capycoder acknowledges each once it's in flash
+        let mut sent = 0;
+        for window in image.chunks(WINDOW_LEN as usize) {
+            for piece in window.chunks(MAX_DATA_LEN) {
+                perf.write(&data, piece, WriteType::WithoutResponse).await?;
+            }
+            sent += window.len() as u32;
+            expect_received(&mut notifications, &control, sent).await?;
+            progress((u64::from(sent) * 100 / u64::from(size)) as u8);
+        }
+
+        self.send_dfu_command(&control, &DfuCommand::Finish).await?;
+        let installed = next_dfu_status(&mut notifications, &control);
+        match time::timeout(DFU_INSTALL_TIMEOUT, installed)
+            .await
+            .map_err(|_| anyhow!("capycoder didn't install the update in time"))??
+        {
+            DfuStatus::Installed => info!("capycoder installed the update, restarting"),
+            status => return Err(anyhow!("capycoder didn't install the update: {status:?}")),
+        }
+        drop(notifications);
+
+        // it restarts on its own, hang up so our side notices straight away
+        if let Err(e) = self.disconnect().await {
+            warn!("failed to disconnect from capycoder: {e}");
+        }
+        self.peripheral = None;
+
+        let running = time::timeout(DFU_RESTART_TIMEOUT, self.reconnect_firmware_version())
+            .await
+            .map_err(|_| anyhow!("capycoder didn't come back after the update"))?;
+        let confirmed = if running == version {
+            self.confirm_firmware().await
+        } else {
+            Err(anyhow!(
+                "capycoder went back to firmware {running}, the update to {version} didn't stick"
+            ))
+        };
+        if let Err(e) = self.disconnect().await {
+            warn!("failed to disconnect from capycoder: {e}");
+        }
+
+        confirmed.map(|()| version)
+    }
+
+    /// Tells a freshly updated capycoder its firmware works, or it rolls back unless it
+    /// reaches the server.
+    async fn confirm_firmware(&mut self) -> Result<()> {
+        // the restart dropped the old link, the confirmation needs an authenticated one
+        self.pair().await?;
+
+        let perf = self.peripheral()?;
+        let characteristic = find_characteristic(perf, short_uuid(CONTROL_CHARACTERISTIC))?;
+        perf.write(
+            &characteristic,
+            &[ControlOpcode::ConfirmFirmware as u8],
+            WriteType::WithResponse,
+        )
+        .await
+        .map_err(|e| anyhow!("confirming the firmware update failed: {e}"))?;
+
+        info!("confirmed the firmware update");
+        Ok(())
+    }
+
+    /// Reconnects to a restarting capycoder and reads its firmware version,
+    /// retrying until it's back up.
+    async fn reconnect_firmware_version(&mut self) -> String {
+        loop {
+            time::sleep(Duration::from_secs(5)).await;
+            match self.firmware_version().await {
+                Ok(version) => return version,
+                Err(e) => info!("capycoder not back yet: {e:#}"),
+            }
+        }
+    }
+
+    /// Connects and reads the firmware version the device runs.
+    pub async fn firmware_version(&mut self) -> Result<String> {
+        self.connect().await.context("failed to connect")?;
+
+        let perf = self.peripheral()?;
+        let revision = find_service_characteristic(
+            perf,
+            uuid_from_u16(DEVICE_INFORMATION_SERVICE),
+            uuid_from_u16(FIRMWARE_REVISION_CHARACTERISTIC),
+        )?;
+        let value = perf.read(&revision).await?;
+        Ok(String::from_utf8_lossy(&value).into_owned())
+    }
+
+    async fn send_dfu_command(
+        &self,
+        characteristic: &Characteristic,
+        command: &DfuCommand,
+    ) -> Result<()> {
+        let perf = self.peripheral()?;
+
+        let mut buf = [0u8; DfuCommand::MAX_ENCODED_LEN];
+        let message = command
+            .encode(&mut buf)
+            .map_err(|e| anyhow!("failed to encode firmware update command: {e}"))?;
+
+        for chunk in chunks(message) {
+            let mut buf = [0u8; MAX_CHUNK_LEN];
+            let len = chunk.write(&mut buf);
+            perf.write(characteristic, &buf[..len], WriteType::WithResponse)
+                .await?;
+        }
+        Ok(())
+    }
+
+    pub async fn send_config_data(&mut self, provisioning: &Provisioning) -> Result<()> {
+        let perf = self.peripheral()?;
+        let characteristic = find_characteristic(perf, TOKENS_CHARACTERISTIC)?;
+
+        let mut buf = [0u8; Provisioning::MAX_ENCODED_LEN];
+        let message = provisioning
+            .encode(&mut buf)
+            .map_err(|e| anyhow!("failed to encode provisioning message: {e}"))?;
+
+        // the message is bigger than one ATT write, so it goes over in chunks
+        for chunk in chunks(message) {
+            let mut buf = [0u8; MAX_CHUNK_LEN];
+            let len = chunk.write(&mut buf);
+            perf.write(&characteristic, &buf[..len], WriteType::WithResponse)
+                .await?;
+        }
+        info!("wrote provisioning message to capycoder");
+
+        Ok(())
+    }
+
+    fn peripheral(&self) -> Result<&Peripheral> {
+        self.peripheral
+            .as_ref()
+            .ok_or(anyhow!("not connected to a capycoder!"))
+    }
+}
+
+/// Builds the provisioning message, checking every value fits on the device.
+pub fn provisioning(
+    ssid: &str,
+    password: &str,
+    github_token: &str,
+    github_user: &str,
+    server_url: &str,
+    server_token: &str,
+    device_name: &str,
+) -> Result<Provisioning> {
+    fn bounded<const N: usize>(value: &str, what: &str) -> Result<heapless::String<N>> {
+        heapless::String::try_from(value)
+            .map_err(|_| anyhow!("{what} is too long, the device fits at most {N} bytes"))
+    }
+
+    Ok(Provisioning {
+        wifi_credentials: WifiCredentials {
+            ssid: bounded(ssid, "wifi name")?,
+            password: bounded(password, "wifi password")?,
+        },
+        tokens: Tokens {
+            github: bounded(github_token, "github token")?,
+            server: bounded(server_token, "server token")?,
+        },
+        device_name: bounded(device_name, "device name")?,
+        github_user: bounded(github_user, "github username")?,
+        // let the device pick
+        refresh_secs: 0,
+        server_url: bounded(server_url.trim_end_matches('/'), "server url")?,
+        full_refresh_every: 0,
+    })
+}
+
+/// Decodes a release signature the way `sign-firmware` writes it, in hex.
+pub fn parse_signature(hex: &str) -> Result<dfu::Signature> {
+    let mut signature = [0u8; 64];
+    hex::decode_to_slice(hex.trim(), &mut signature)
+        .map_err(|e| anyhow!("that's not a firmware signature: {e}"))?;
+    Ok(dfu::Signature(signature))
+}
+
+/// Human readable name of a network's security, for showing in the network list.
+pub fn auth_label(auth: AuthMethod) -> &'static str {
+    match auth {
+        AuthMethod::Open => "Open",
+        AuthMethod::Wep => "WEP",
+        AuthMethod::Wpa => "WPA",
+        AuthMethod::Wpa2Personal => "WPA2",
+        AuthMethod::WpaWpa2Personal => "WPA/WPA2",
+        AuthMethod::Wpa2Enterprise => "WPA2 Enterprise",
+        AuthMethod::Wpa3Personal => "WPA3",
+        AuthMethod::Wpa2Wpa3Personal => "WPA2/WPA3",
+        AuthMethod::WapiPersonal => "WAPI",
+        AuthMethod::Unknown => "Unknown",
+    }
+}
+
+/// Expands one of our 16 bit `ble_types` UUIDs into a full bluetooth UUID.
+///
+/// trouble stores short UUIDs little-endian, so the bytes are flipped here.
+fn short_uuid(raw: [u8; 2]) -> Uuid {
+    uuid_from_u16(u16::from_le_bytes(raw))
+}
+
+/// Looks up one of the config service's characteristics, services have to be discovered first.
+fn find_characteristic(perf: &Peripheral, uuid: Uuid) -> Result<Characteristic> {
+    find_service_characteristic(perf, short_uuid(CONFIG_SERVICE_UUID), uuid)
+}
+
+fn find_service_characteristic(
+    perf: &Peripheral,
+    service_uuid: Uuid,
+    uuid: Uuid,
+) -> Result<Characteristic> {
+    perf.characteristics()
+        .into_iter()
+        .find(|c| c.service_uuid == service_uuid && c.uuid == uuid)
+        .ok_or(anyhow!("capycoder is missing characteristic {uuid}!"))
+}
+
+/// Waits for the next firmware update status, failing if the device gave up on the update.
+async fn next_dfu_status(
+    notifications: &mut (impl Stream<Item = ValueNotification> + Unpin),
+    control: &Characteristic,
+) -> Result<DfuStatus> {
+    while let Some(notification) = notifications.next().await {
+        if notification.uuid != control.uuid {
+            continue;
+        }
+        match DfuStatus::decode(&notification.value) {
+            Ok(DfuStatus::Failed(e)) => return Err(anyhow!("capycoder gave up the update: {e:?}")),
+            Ok(status) => return Ok(status),
+            Err(e) => warn!("invalid firmware update status: {e}"),
+        }
+    }
+    Err(anyhow!("capycoder went away during the update"))
+}
+
+/// Waits for the device to acknowledge the first `sent` bytes of a firmware update.
+async fn expect_received(
+    notifications: &mut (impl Stream<Item = ValueNotification> + Unpin),
+    control: &Characteristic,
+    sent: u32,
+) -> Result<()> {
+    let status = time::timeout(DFU_WINDOW_TIMEOUT, next_dfu_status(notifications, control))
+        .await
+        .map_err(|_| anyhow!("capycoder stopped acknowledging the update at {sent} bytes"))??;
+
+    match status {
+        DfuStatus::Received(received) if received == sent => Ok(()),
+        status => Err(anyhow!(
+            "capycoder lost part of the update, sent {sent} bytes but it reported {status:?}"
+        )),
     }
 }
 
@@ -1,29 +1,46 @@
 use std::time::Duration;
 
+use ble_types::ProvisioningStatus;
 use chrono::{DateTime, TimeDelta, Utc};
 use jsonwebtoken::{Algorithm, EncodingKey, Header};
 use reqwest::StatusCode;
 use serde::Deserialize;
 use serde_json::{json, Value};
+use tauri::{AppHandle, Runtime};
 use tokio::io::AsyncWriteExt;
 use tokio::process::Command;
 
+use crate::ble::CapyCoder;
 use crate::types::{
-    AgentConfig, AgentStatus, ClaudeMetricsRequest, ClaudeMetricsSnapshot,
-    ClaudeQuestionRequest, ClaudeQuestionResponse, ClaudeUsage, ClaudeVoiceRequest,
-    ClaudeVoiceResponse, LivekitTokenRequest, LivekitTokenResponse, PushClaudeMetricsRequest,
+    AgentConfig, AgentStatus, ClaudeMetricsRequest, ClaudeMetricsSnapshot, ClaudeQuestionRequest,
+    ClaudeQuestionResponse, ClaudeUsage, ClaudeVoiceRequest, ClaudeVoiceResponse, DeviceNetwork,
+    LivekitTokenRequest, LivekitTokenResponse, PushClaudeMetricsRequest,
 };
 
 const PYTHON_METRICS_SCRIPT: &str = include_str!("python/collect_metrics.py");
 
-#[taurpc::procedures(export_to = "../src/types.ts")]
+#[taurpc::procedures(export_to = "../src/types.ts", event_trigger = ApiEventTrigger)]
 trait Api {
     async fn connect_device(
         github_token: String,
+        github_user: String,
+        server_url: String,
+        server_token: String,
         wifi_name: String,
         wifi_pass: String,
     ) -> Result<String, String>;
 
+    async fn scan_device_networks() -> Result<Vec<DeviceNetwork>, String>;
+
+    async fn update_device_firmware<R: Runtime>(
+        app_handle: AppHandle<R>,
+        image_path: String,
+    ) -> Result<String, String>;
+
+    /// How much of the image `update_device_firmware` sent so far, in percent.
+    #[taurpc(event)]
+    async fn firmware_update_progress(percent: u8);
+
     async fn collect_claude_metrics(
         request: ClaudeMetricsRequest,
     ) -> Result<ClaudeMetricsSnapshot, String>;
@@ -129,16 +146,96 @@ impl Api for ApiImpl {
     async fn connect_device(
         self,
         github_token: String,
+        github_user: String,
+        server_url: String,
+        server_token: String,
         wifi_name: String,
         wifi_pass: String,
     ) -> Result<String, String> {
-        // how to wait for 3 seconds
-        tokio::time::sleep(Duration::from_secs(3)).await;
-        if wifi_pass == "cappy" {
-            Ok("connected".to_string())
-        } else {
-            Err("BECOME CAPPY".to_string())
+        let provisioning = ble::provisioning(
+            &wifi_name,
+            &wifi_pass,
+            &github_token,
+            &github_user,
+            &server_url,
+            &server_token,
+            "",
+        )
+        .map_err(|err| err.to_string())?;
+
+        let mut capycoder = CapyCoder::default();
+
+        let sent = capycoder
+            .provision(&provisioning)
+            .await
+            .map_err(|err| format!("failed to provision device: {err:#}"));
+
+        // always try to hang up, even if something above failed
+        if let Err(err) = capycoder.disconnect().await {
+            log::warn!("failed to disconnect from device: {err}");
+        }
+
+        sent.map(|status| match status {
+            ProvisioningStatus::ServerReachable => "connected".to_string(),
+            _ => "joined wifi, but the server isn't reachable yet".to_string(),
+        })
+    }
+
+    async fn scan_device_networks(self) -> Result<Vec<DeviceNetwork>, String> {
+        let mut capycoder = CapyCoder::default();
+
+        let scanned = capycoder
+            .scan_networks()
+            .await
+            .map_err(|err| format!("failed to scan for networks: {err:#}"));
+
+        if let Err(err) = capycoder.disconnect().await {
+            log::warn!("failed to disconnect from device: {err}");
         }
+
+        Ok(scanned?
+            .into_iter()
+            .map(|network| DeviceNetwork {
+                ssid: network.ssid.to_string(),
+                rssi: network.rssi,
+                channel: network.channel,
+                auth: ble::auth_label(network.auth).to_string(),
+            })
+            .collect())
+    }
+
+    async fn update_device_firmware<R: Runtime>(
+        self,
+        app_handle: AppHandle<R>,
+        image_path: String,
+    ) -> Result<String, String> {
+        let image = tokio::fs::read(&image_path)
+            .await
+            .map_err(|err| format!("failed to read {image_path}: {err}"))?;
+        // sign-firmware writes the signature next to the image
+        let signature_path = format!("{image_path}.sig");
+        let signature = tokio::fs::read_to_string(&signature_path)
+            .await
+            .map_err(|err| format!("failed to read the signature {signature_path}: {err}"))?;
+        let signature = ble::parse_signature(&signature).map_err(|err| err.to_string())?;
+        let trigger = ApiEventTrigger::new(app_handle);
+
+        let mut capycoder = CapyCoder::default();
+
+        let updated = capycoder
+            .update_firmware(&image, signature, |percent| {
+                if let Err(err) = trigger.firmware_update_progress(percent) {
+                    log::warn!("failed to report firmware update progress: {err}");
+                }
+            })
+            .await
+            .map_err(|err| format!("failed to update device firmware: {err:#}"));
+
+        if let Err(err) = capycoder.disconnect().await {
+            log::warn!("failed to disconnect from device: {err}");
+        }
+
+        updated
     }
 
     async fn collect_claude_metrics(
@@ -728,6 +825,7 @@ fn audio_format_to_mime(format: &str) -> String {
     }
 }
 
+mod ble;
 mod types;
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -114,3 +114,11 @@ pub struct AgentStatus {
     pub running: bool,
     pub pid: Option<u32>,
 }
+
+#[taurpc::ipc_type]
+pub struct DeviceNetwork {
+    pub ssid: String,
+    pub rssi: i8,
+    pub channel: u8,
+    pub auth: String,
+}
@@ -22,24 +22,29 @@ export type ClaudeVoiceRequest = { api_key: string; audio_base64: string; audio_
 
 export type ClaudeVoiceResponse = { answer_text: string; answer_audio_base64: string | null; answer_audio_mime_type: string | null; transcript: string | null; model: string; stop_reason: string | null; usage: ClaudeUsage | null }
 
+export type DeviceNetwork = { ssid: string; rssi: number; channel: number; auth: string }
+
 export type LivekitTokenRequest = { api_key: string; api_secret: string; identity: string; room: string; name: string | null; metadata: string | null; ttl_seconds: number | null; can_publish: boolean | null; can_subscribe: boolean | null; can_publish_data: boolean | null }
 
 export type LivekitTokenResponse = { token: string; expires_at: string }
 
 export type PushClaudeMetricsRequest = { metrics: ClaudeMetricsSnapshot; server_url: string; auth_token: string | null }
 
-const ARGS_MAP = { '':'{"ask_claude":["request"],"ask_claude_voice":["request"],"collect_claude_metrics":["request"],"connect_device":["github_token","wifi_name","wifi_pass"],"generate_livekit_token":["request"],"get_agent_status":[],"load_agent_config":[],"push_claude_metrics":["request"],"save_agent_config":["config"],"start_agent":[],"stop_agent":[]}' }
+const ARGS_MAP = { '':'{"ask_claude":["request"],"ask_claude_voice":["request"],"collect_claude_metrics":["request"],"connect_device":["github_token","github_user","server_url","server_token","wifi_name","wifi_pass"],"firmware_update_progress":["percent"],"generate_livekit_token":["request"],"get_agent_status":[],"load_agent_config":[],"push_claude_metrics":["request"],"save_agent_config":["config"],"scan_device_networks":[],"start_agent":[],"stop_agent":[],"update_device_firmware":["image_path"]}' }
 export type Router = { "": {ask_claude: (request: ClaudeQuestionRequest) => Promise<ClaudeQuestionResponse>, 
 ask_claude_voice: (request: ClaudeVoiceRequest) => Promise<ClaudeVoiceResponse>, 
 collect_claude_metrics: (request: ClaudeMetricsRequest) => Promise<ClaudeMetricsSnapshot>, 
-connect_device: (githubToken: string, wifiName: string, wifiPass: string) => Promise<string>, 
+connect_device: (githubToken: string, githubUser: string, serverUrl: string, serverToken: string, wifiName: string, wifiPass: string) => Promise<string>, 
+firmware_update_progress: (percent: number) => Promise<void>, 
 generate_livekit_token: (request: LivekitTokenRequest) => Promise<LivekitTokenResponse>, 
 get_agent_status: () => Promise<AgentStatus>, 
 load_agent_config: () => Promise<AgentConfig | null>, 
 push_claude_metrics: (request: PushClaudeMetricsRequest) => Promise<ClaudeMetricsSnapshot>, 
 save_agent_config: (config: AgentConfig) => Promise<null>, 
+scan_device_networks: () => Promise<DeviceNetwork[]>, 
 start_agent: () => Promise<AgentStatus>, 
-stop_agent: () => Promise<null>} };
+stop_agent: () => Promise<null>, 
+update_device_firmware: (imagePath: string) => Promise<string>} };
 
 
 export const createTauRPCProxy = () => createProxy<Router>(ARGS_MAP)
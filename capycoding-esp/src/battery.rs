@@ -0,0 +1,96 @@
+//! Battery monitoring for the battery powered build.
+//!
+//! The cell sits behind a divider of two equal resistors on GPIO4, the only ADC1 pin
+//! the display leaves free once its clock moves to GPIO6.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Timer};
+use esp_hal::analog::adc::{Adc, AdcCalCurve, AdcConfig, Attenuation};
+use esp_hal::peripherals::{ADC1, GPIO4};
+use log::info;
+
+pub use capycoding_ui::Battery;
+
+use crate::{Message, PUB_SUB_CHANNEL};
+
+/// Time between measurements.
+const INTERVAL: Duration = Duration::from_secs(60);
+/// Readings averaged per measurement, the ADC is noisy.
+const SAMPLES: u32 = 8;
+/// The divider halves the cell voltage to keep it in the ADC's range.
+const DIVIDER: u32 = 2;
+/// Goes low at this charge...
+const LOW_PERCENT: u8 = 10;
+/// ...and only recovers above this one, so the screen doesn't flip back and forth.
+const RECOVERED_PERCENT: u8 = 15;
+
+/// Resting voltage of a LiPo cell at a given charge, highest first.
+const DISCHARGE_CURVE: [(u32, u8); 9] = [
+    (4200, 100),
+    (4100, 90),
+    (4000, 80),
+    (3900, 60),
+    (3800, 40),
+    (3700, 20),
+    (3600, 10),
+    (3500, 5),
+    (3300, 0),
+];
+
+/// The latest measurement, for the BLE Battery Service. Only the BLE task receives it.
+pub static BATTERY: Watch<CriticalSectionRawMutex, Battery, 1> = Watch::new();
+
+/// Measures the battery every [`INTERVAL`], publishing the charge whenever it changes.
+#[embassy_executor::task]
+pub async fn battery_task(adc: ADC1<'static>, pin: GPIO4<'static>) {
+    info!("Battery task started!");
+    let mut config = AdcConfig::new();
+    let mut pin =
+        config.enable_pin_with_cal::<_, AdcCalCurve<ADC1<'static>>>(pin, Attenuation::_11dB);
+    let mut adc = Adc::new(adc, config).into_async();
+    let mut last: Option<Battery> = None;
+
+    loop {
+        let mut millivolts = 0;
+        for _ in 0..SAMPLES {
+            millivolts += u32::from(adc.read_oneshot(&mut pin).await);
+        }
+        let millivolts = millivolts / SAMPLES * DIVIDER;
+
+        let percent = charge(millivolts);
+        let low = match last {
+            Some(last) if last.low => percent <= RECOVERED_PERCENT,
+            _ => percent <= LOW_PERCENT,
+        };
+        let battery = Battery { percent, low };
+
+        if last != Some(battery) {
+            info!("[battery] {millivolts}mV, {percent}%");
+            last = Some(battery);
+            BATTERY.sender().send(battery);
+            PUB_SUB_CHANNEL
+                .immediate_publisher()
+                .publish_immediate(Message::Battery(battery));
+        }
+
+        Timer::after(INTERVAL).await;
+    }
+}
+
+/// Interpolates the charge left from the cell voltage along [`DISCHARGE_CURVE`].
+fn charge(millivolts: u32) -> u8 {
+    let (full_mv, _) = DISCHARGE_CURVE[0];
+    if millivolts >= full_mv {
+        return 100;
+    }
+
+    for pair in DISCHARGE_CURVE.windows(2) {
+        let [(high_mv, high), (low_mv, low)] = [pair[0], pair[1]];
+        if millivolts >= low_mv {
+            let span = u32::from(high - low);
+            return low + ((millivolts - low_mv) * span / (high_mv - low_mv)) as u8;
+        }
+    }
+    0
+}
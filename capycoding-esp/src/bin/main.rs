@@ -7,14 +7,19 @@
 )]
 
 use capycoding_esp::ble::ble_task;
+use capycoding_esp::button::button_task;
+#[cfg(feature = "battery")]
+use capycoding_esp::battery::battery_task;
+#[cfg(feature = "deep-sleep")]
+use capycoding_esp::power::power_task;
+use capycoding_esp::ota::ota_health_task;
 use capycoding_esp::wifi::{connection, net_task, wifi_task};
 use capycoding_esp::{CapyConfig, WeactTermInitPins, ui_task};
 use embassy_executor::Spawner;
 use embassy_net::dns::DnsSocket;
 use embassy_net::tcp::client::{self, TcpClient, TcpClientState};
 use embassy_net::{Config, DhcpConfig, Runner, Stack, StackResources};
-use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
-use embassy_sync::pubsub::PubSubChannel;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_time::{Duration, Timer};
 use esp_hal::clock::CpuClock;
 use esp_hal::rng::Rng;
@@ -29,7 +34,7 @@ use esp_storage::FlashStorage;
 use esp_backtrace as _;
 
 use capycoding_esp::alloc::string::String;
-use log::info;
+use log::{error, info};
 use reqwless::client::{HttpClient, TlsConfig};
 use static_cell::StaticCell;
 
@@ -39,20 +44,13 @@ esp_bootloader_esp_idf::esp_app_desc!();
 
 use embassy_sync::mutex::Mutex;
 
-const SSID: &str = "Surendra S21";
-const PASSWORD: &str = "Surendra2006";
-
 pub static CONFIG: StaticCell<Mutex<CriticalSectionRawMutex, Option<CapyConfig>>> =
     StaticCell::new();
 
-static RADIO: StaticCell<esp_radio::Controller<'static>> = StaticCell::new();
-static PUB_SUB_CHANNEL: static_cell::StaticCell<PubSubChannel<NoopRawMutex, Message, 20, 3, 1>> =
-    static_cell::StaticCell::new();
+pub static FLASH: StaticCell<Mutex<CriticalSectionRawMutex, FlashStorage<'static>>> =
+    StaticCell::new();
 
-#[derive(Copy, Clone)]
-pub enum Message {
-    Connected,
-}
+static RADIO: StaticCell<esp_radio::Controller<'static>> = StaticCell::new();
 
 macro_rules! mk_static {
     ($t:ty,$val:expr) => {{
@@ -89,7 +87,11 @@ async fn main(spawner: Spawner) -> () {
 
     // init for term
     let mosi_pin = peripherals.GPIO5;
+    // battery builds need GPIO4 for the ADC
+    #[cfg(not(feature = "battery"))]
     let sclk_pin = peripherals.GPIO4;
+    #[cfg(feature = "battery")]
+    let sclk_pin = peripherals.GPIO6;
 
     let spi_bus = Spi::new(
         peripherals.SPI2,
@@ -110,13 +112,22 @@ async fn main(spawner: Spawner) -> () {
 
     let mut flash = FlashStorage::new(peripherals.FLASH);
 
-    let state = CapyConfig::load(&mut flash);
+    // a broken config store shouldn't stop the device from booting, show it on screen instead
+    let (state, config_error) = match CapyConfig::load(&mut flash) {
+        Ok(state) => (state, None),
+        Err(e) => {
+            error!("Failed to load config: {e}");
+            (None, Some(e))
+        }
+    };
 
     // spawn tasks
     let capyconfig = CONFIG.init(Mutex::new(state));
 
     let capy_ref = &*capyconfig;
 
+    let flash_ref = &*FLASH.init(Mutex::new(flash));
+
     let (wifi_controller, ifaces) =
         esp_radio::wifi::new(radio, peripherals.WIFI, WifiConfig::default()).unwrap();
 
@@ -124,7 +135,6 @@ async fn main(spawner: Spawner) -> () {
 
     let rng = Rng::new();
     let net_seed = rng.random() as u64 | ((rng.random() as u64) << 32);
-    let tls_seed = rng.random() as u64 | ((rng.random() as u64) << 32);
 
     let dhcp_config = DhcpConfig::default();
     let config = embassy_net::Config::dhcpv4(dhcp_config);
@@ -138,18 +148,40 @@ async fn main(spawner: Spawner) -> () {
 
     // BLE handler
     spawner
-        .spawn(ble_task(radio, peripherals.BT, capy_ref))
+        .spawn(ble_task(radio, peripherals.BT, rng, capy_ref, flash_ref))
         .unwrap();
 
     // UI handler
     spawner
-        .spawn(ui_task(spi_bus, term_init_pins, capy_ref))
+        .spawn(ui_task(spi_bus, term_init_pins, capy_ref, config_error))
         .unwrap();
 
+    // factory reset button
+    spawner
+        .spawn(button_task(peripherals.GPIO9, capy_ref, flash_ref))
+        .unwrap();
+
+    #[cfg(feature = "deep-sleep")]
+    spawner
+        .spawn(power_task(peripherals.LPWR, capy_ref))
+        .unwrap();
+
+    #[cfg(feature = "battery")]
+    spawner
+        .spawn(battery_task(peripherals.ADC1, peripherals.GPIO4))
+        .unwrap();
+
+    // confirms or rolls back a fresh firmware update
+    spawner.spawn(ota_health_task(flash_ref)).unwrap();
+
     // wifi util tasks
-    spawner.spawn(connection(wifi_controller)).unwrap();
+    spawner
+        .spawn(connection(wifi_controller, capy_ref))
+        .unwrap();
     spawner.spawn(net_task(runner)).unwrap();
 
     // main wifi task
-    spawner.spawn(wifi_task(stack, tls_seed)).unwrap();
+    spawner
+        .spawn(wifi_task(stack, Rng::new(), capy_ref, flash_ref))
+        .unwrap();
 }
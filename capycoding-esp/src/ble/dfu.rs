@@ -0,0 +1,132 @@
+//! Firmware images pushed by the app over BLE, see `ble_types::dfu` for the protocol.
+//!
+//! Only reachable over an authenticated link, and like updates from the server the image
+//! has to be signed with the release key.
+
+use ble_types::dfu::{DfuCommand, DfuError, DfuStatus, WINDOW_LEN};
+use heapless::Vec;
+use log::{info, warn};
+
+use crate::CapyFlashHandle;
+use crate::ota::{self, OtaError, Update};
+
+impl From<OtaError> for DfuError {
+    fn from(e: OtaError) -> Self {
+        match e {
+            OtaError::TooLarge(_) => DfuError::TooLarge,
+            OtaError::Incomplete => DfuError::Incomplete,
+            OtaError::HashMismatch => DfuError::HashMismatch,
+            OtaError::Busy => DfuError::Busy,
+            OtaError::NoReleaseKey | OtaError::BadSignature => DfuError::BadSignature,
+            OtaError::Flash(_) | OtaError::Write => DfuError::Flash,
+            // a central hands us the hash and signature as bytes, there's no release to fetch or parse
+            e @ (OtaError::Api(_) | OtaError::InvalidHash | OtaError::InvalidSignature) => {
+                unreachable!("only updating from the server fails with {e:?}")
+            }
+        }
+    }
+}
+
+/// The update a central is pushing, if any.
+///
+/// Data is collected into windows of [`WINDOW_LEN`] bytes, each one is written to flash and
+/// acknowledged in one go.
+pub(super) struct Dfu {
+    flash_handle: CapyFlashHandle,
+    update: Option<Update>,
+    window: Vec<u8, { WINDOW_LEN as usize }>,
+}
+
+impl Dfu {
+    pub(super) fn new(flash_handle: CapyFlashHandle) -> Self {
+        Self {
+            flash_handle,
+            update: None,
+            window: Vec::new(),
+        }
+    }
+
+    /// Carries out `command`, returning the status to notify back.
+    pub(super) async fn command(&mut self, command: DfuCommand) -> Option<DfuStatus> {
+        match command {
+            DfuCommand::Start {
+                size,
+                sha256,
+                signature,
+            } => {
+                info!("[dfu] starting a {size} byte update");
+                // let go of an earlier update first, or it would keep this one out
+                self.abort();
+                if let Err(e) = ota::verify_signature(&sha256, &signature.0) {
+                    return Some(self.fail(e));
+                }
+                match Update::begin(self.flash_handle, size, sha256).await {
+                    Ok(update) => {
+                        self.update = Some(update);
+                        Some(DfuStatus::Received(0))
+                    }
+                    Err(e) => Some(self.fail(e)),
+                }
+            }
+            DfuCommand::Finish => {
+                let Some(mut update) = self.update.take() else {
+                    return Some(DfuStatus::Failed(DfuError::NotStarted));
+                };
+                // the last window is written as soon as it's complete, this is only a
+                // leftover if the image ended early
+                if !self.window.is_empty()
+                    && let Err(e) = update.write(&self.window).await
+                {
+                    return Some(self.fail(e));
+                }
+                self.window.clear();
+
+                match update.install().await {
+                    Ok(()) => Some(DfuStatus::Installed),
+                    Err(e) => Some(self.fail(e)),
+                }
+            }
+            DfuCommand::Abort => {
+                info!("[dfu] update aborted");
+                self.abort();
+                None
+            }
+        }
+    }
+
+    /// Takes the next piece of the image, returning a status to notify back once a window
+    /// is written or the update failed.
+    pub(super) async fn data(&mut self, data: &[u8]) -> Option<DfuStatus> {
+        let Some(update) = self.update.as_mut() else {
+            return Some(DfuStatus::Failed(DfuError::NotStarted));
+        };
+
+        let received = update.written() + self.window.len() as u32;
+        if self.window.extend_from_slice(data).is_err() {
+            // the central didn't wait for its window to be acknowledged
+            return Some(self.fail(OtaError::TooLarge(received + data.len() as u32)));
+        }
+        if !self.window.is_full() && received + (data.len() as u32) < update.size() {
+            return None;
+        }
+
+        let written = update.write(&self.window).await;
+        self.window.clear();
+        match written {
+            Ok(()) => Some(DfuStatus::Received(update.written())),
+            Err(e) => Some(self.fail(e)),
+        }
+    }
+
+    fn fail(&mut self, e: OtaError) -> DfuStatus {
+        warn!("[dfu] update failed: {:?}", e);
+        self.abort();
+        DfuStatus::Failed(e.into())
+    }
+
+    fn abort(&mut self) {
+        // dropping the update abandons it
+        self.update = None;
+        self.window.clear();
+    }
+}
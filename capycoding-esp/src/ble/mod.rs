@@ -1,19 +1,35 @@
-use ble_types::{PERIPHERAL_ADVERTISEMENT, PERIPHERAL_NAME};
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_sync::pubsub::PubSubChannel;
-use embassy_time::Timer;
+use ble_types::chunk::{Chunk, MAX_CHUNK_LEN, Reassembler, chunks};
+use ble_types::dfu::{DfuCommand, DfuStatus, MAX_DATA_LEN};
+use ble_types::{
+    ControlOpcode, PERIPHERAL_ADVERTISEMENT, PERIPHERAL_NAME, Provisioning, ProvisioningStatus,
+    ScannedNetwork,
+};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::Subscriber;
+use embassy_sync::watch::Receiver;
 use esp_hal::peripherals;
+use esp_hal::rng::Rng;
 use esp_radio::Controller as RadioController;
 use esp_radio::ble::controller::BleConnector;
-use log::{info, warn};
+use log::{error, info, warn};
 use trouble_host::{HostResources, prelude::DefaultPacketPool};
 
 use embassy_futures::join::join;
-use embassy_futures::select::select;
+use embassy_futures::select::{Either, select, select4};
 #[allow(unused_imports)]
 use trouble_host::prelude::*;
 
-use crate::CapyConfigHandle;
+use crate::battery::{BATTERY, Battery};
+use crate::ota::{self, CURRENT_VERSION};
+use crate::power::ADVERTISE;
+use crate::wifi::{SCAN_REQUESTED, SCAN_RESULTS, WIFI_CREDENTIALS_CHANGED, WIFI_STATUS};
+use crate::{
+    CapyConfig, CapyConfigHandle, CapyFlashHandle, Message, PUB_SUB_CHANNEL, factory_reset,
+};
+
+mod dfu;
+
+use dfu::Dfu;
 
 const CONNECTIONS_MAX: usize = 1;
 const L2CAP_CHANNELS_MAX: usize = 1;
@@ -21,27 +37,74 @@ const L2CAP_CHANNELS_MAX: usize = 1;
 #[gatt_server]
 struct Server {
     config_service: ConfigService,
+    battery_service: BatteryService,
+    device_info_service: DeviceInfoService,
+    dfu_service: DfuService,
+}
+
+/// The standard Battery Service, so the OS shows the charge next to the device.
+#[gatt_service(uuid = service::BATTERY)]
+struct BatteryService {
+    /// Charge left in percent, stays at zero on builds without a battery monitor.
+    #[characteristic(uuid = characteristic::BATTERY_LEVEL, read, notify)]
+    level: u8,
+}
+
+/// The standard Device Information Service, the app checks the firmware version with it.
+#[gatt_service(uuid = service::DEVICE_INFORMATION)]
+struct DeviceInfoService {
+    #[characteristic(uuid = characteristic::FIRMWARE_REVISION_STRING, read)]
+    firmware_revision: heapless::String<32>,
+}
+
+/// Takes firmware images from the app, see `ble_types::dfu`.
+#[gatt_service(uuid = ble_types::dfu::SERVICE_UUID)]
+struct DfuService {
+    /// Takes postcard encoded `ble_types::dfu::DfuCommand`s, split up with `ble_types::chunk`,
+    /// and notifies a `ble_types::dfu::DfuStatus` back.
+    #[characteristic(uuid = ble_types::dfu::CONTROL_CHARACTERISTIC, write, notify)]
+    control: heapless::Vec<u8, MAX_CHUNK_LEN>,
+
+    /// Takes the image itself.
+    #[characteristic(uuid = ble_types::dfu::DATA_CHARACTERISTIC, write_without_response)]
+    data: heapless::Vec<u8, MAX_DATA_LEN>,
 }
 
 #[gatt_service(uuid = ble_types::CONFIG_SERVICE_UUID)]
 struct ConfigService {
-    #[characteristic(uuid = ble_types::WIFI_SSID_CHARACTERISTIC, write, read, notify)]
-    wifi_ssid: heapless::Vec<u8, 24>,
+    /// Takes a postcard encoded `ble_types::Provisioning` message, split up with `ble_types::chunk`.
+    #[characteristic(uuid = ble_types::TOKENS_CHARACTERISTIC_LE, write)]
+    provisioning: heapless::Vec<u8, MAX_CHUNK_LEN>,
 
-    #[characteristic(uuid = ble_types::WIFI_PASSWORD_CHARACTERISTIC, write, read, notify)]
-    wifi_password: heapless::Vec<u8, 24>,
+    /// Takes a single `ControlOpcode` byte.
+    #[characteristic(uuid = ble_types::CONTROL_CHARACTERISTIC, write)]
+    control: u8,
 
-    #[characteristic(uuid = ble_types::GITHUB_TOKEN_CHARACTERISTIC, write, read, notify)]
-    github_token: heapless::Vec<u8, 24>,
+    /// The latest `ProvisioningStatus`, notified as the device joins the provisioned network.
+    #[characteristic(uuid = ble_types::STATUS_CHARACTERISTIC, read, notify)]
+    status: u8,
+
+    /// Any write starts a Wi-Fi scan, every network found is notified back
+    /// as a `ble_types::ScannedNetwork`, split up with `ble_types::chunk`.
+    #[characteristic(uuid = ble_types::SCAN_CHARACTERISTIC, write, notify)]
+    scan: heapless::Vec<u8, MAX_CHUNK_LEN>,
 }
 
+type MessageSubscriber = Subscriber<'static, CriticalSectionRawMutex, Message, 20, 3, 1>;
+
+type StatusReceiver = Receiver<'static, CriticalSectionRawMutex, ProvisioningStatus, 2>;
+
+type BatteryReceiver = Receiver<'static, CriticalSectionRawMutex, Battery, 1>;
+
 type CapyResources = HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX>;
 
 #[embassy_executor::task]
 pub async fn ble_task(
     radio: &'static RadioController<'static>,
     bt: peripherals::BT<'static>,
+    mut rng: Rng,
     config_handle: CapyConfigHandle,
+    flash_handle: CapyFlashHandle,
 ) {
     info!("BLE task started!");
     let transport = BleConnector::new(radio, bt, Default::default()).unwrap();
@@ -55,7 +118,11 @@ pub async fn ble_task(
 
     let mut resources: HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX> =
         HostResources::new();
-    let stack = trouble_host::new(ble_controller, &mut resources).set_random_address(address);
+    let stack = trouble_host::new(ble_controller, &mut resources)
+        .set_random_address(address)
+        .set_random_generator_seed(&mut rng);
+    // we have a screen to show a passkey on, which gets us authenticated pairing
+    stack.set_io_capabilities(IoCapabilities::DisplayOnly);
     let Host {
         mut peripheral,
         runner,
@@ -68,19 +135,57 @@ pub async fn ble_task(
         appearance: &appearance::power_device::GENERIC_POWER_DEVICE,
     }))
     .unwrap();
+    // the version always fits, semver strings are nowhere near 32 bytes
+    let version = heapless::String::try_from(CURRENT_VERSION).unwrap_or_default();
+    if let Err(e) = server.set(&server.device_info_service.firmware_revision, &version) {
+        warn!("[ble] failed to set the firmware revision: {:?}", e);
+    }
+
+    let mut messages = PUB_SUB_CHANNEL.subscriber().unwrap();
+    let mut wifi_status = WIFI_STATUS.receiver().unwrap();
+    let mut advertise_allowed = ADVERTISE.receiver().unwrap();
+    let mut battery = BATTERY.receiver().unwrap();
 
     let _ = join(ble_co_task(runner), async {
         loop {
-            match advertise(PERIPHERAL_ADVERTISEMENT, &mut peripheral, &server).await {
-                Ok(conn) => {
+            // outside the provisioning window nobody is looking for us, save the power
+            advertise_allowed.get_and(|allowed| *allowed).await;
+            let advertised = select(
+                advertise(PERIPHERAL_ADVERTISEMENT, &mut peripheral, &server),
+                advertise_allowed.changed_and(|allowed| !*allowed),
+            )
+            .await;
+
+            match advertised {
+                Either::Second(_) => info!("[adv] provisioning window closed, stopped advertising"),
+                Either::First(Ok(conn)) => {
+                    // no bonding: keys aren't kept in flash, so a bond wouldn't survive a
+                    // reboot, deep sleep or DFU restart. Every connection pairs fresh instead.
+
+                    // resets that happened while nobody was connected are already dealt with
+                    while messages.try_next_message_pure().is_some() {}
+                    // as are scans the last central asked for
+                    SCAN_RESULTS.reset();
+                    PUB_SUB_CHANNEL
+                        .immediate_publisher()
+                        .publish_immediate(Message::Connected);
+
                     // set up tasks when the connection is established to a central, so they don't run when no one is connected.
-                    let a = gatt_events_task(&server, &conn);
-                    let b = custom_task(&server, &conn, &stack);
+                    let a = gatt_events_task(&server, &conn, config_handle, flash_handle);
+                    let b = status_task(&server, &conn, &mut wifi_status);
+                    let c = reset_task(&conn, &mut messages);
+                    let d = scan_task(&server, &conn);
+                    let e = battery_level_task(&server, &conn, &mut battery);
                     // run until any task ends (usually because the connection has been closed),
                     // then return to advertising state.
-                    select(a, b).await;
+                    select(select4(a, b, c, d), e).await;
+
+                    // don't leave a stale passkey on screen
+                    let publisher = PUB_SUB_CHANNEL.immediate_publisher();
+                    publisher.publish_immediate(Message::PairingDone);
+                    publisher.publish_immediate(Message::Disconnected);
                 }
-                Err(e) => {
+                Either::First(Err(e)) => {
                     panic!("[adv] error: {:?}", e);
                 }
             }
@@ -101,57 +206,152 @@ async fn ble_co_task<C: Controller, P: PacketPool>(mut runner: Runner<'_, C, P>)
 ///
 /// This function will handle the GATT events and process them.
 /// This is how we interact with read and write requests.
+///
+/// Provisioning, control and DFU writes carry secrets, wipe them or replace the firmware,
+/// so they are refused until the link is encrypted by LE Secure Connections pairing with
+/// the passkey shown on screen. Just Works pairing isn't protected against a man in the
+/// middle, writes over such a link are refused just the same. We don't bond, so the
+/// passkey is asked for again on every connection.
 async fn gatt_events_task<P: PacketPool>(
     server: &Server<'_>,
     conn: &GattConnection<'_, '_, P>,
+    config_handle: CapyConfigHandle,
+    flash_handle: CapyFlashHandle,
 ) -> Result<(), Error> {
-    let ssid = &server.config_service.wifi_ssid;
-    let passwd = &server.config_service.wifi_password;
-    let gh_token = &server.config_service.github_token;
+    let provisioning = &server.config_service.provisioning;
+    let control = &server.config_service.control;
+    let scan = &server.config_service.scan;
+    let dfu_control = &server.dfu_service.control;
+    let dfu_data = &server.dfu_service.data;
+    let mut provisioning_chunks = Reassembler::<{ Provisioning::MAX_ENCODED_LEN }>::new();
+    let mut dfu_chunks = Reassembler::<{ DfuCommand::MAX_ENCODED_LEN }>::new();
+    let mut dfu = Dfu::new(flash_handle);
 
     let reason = loop {
         match conn.next().await {
             GattConnectionEvent::Disconnected { reason } => break reason,
+            GattConnectionEvent::PassKeyDisplay(key) => {
+                info!("[gatt] pairing, showing passkey");
+                PUB_SUB_CHANNEL
+                    .immediate_publisher()
+                    .publish_immediate(Message::PairingPasskey(key.value()));
+            }
+            GattConnectionEvent::PairingComplete { security_level, .. } => {
+                info!("[gatt] pairing complete: {:?}", security_level);
+                PUB_SUB_CHANNEL
+                    .immediate_publisher()
+                    .publish_immediate(Message::PairingDone);
+            }
+            GattConnectionEvent::PairingFailed(e) => {
+                warn!("[gatt] pairing failed: {:?}", e);
+                PUB_SUB_CHANNEL
+                    .immediate_publisher()
+                    .publish_immediate(Message::PairingDone);
+            }
             GattConnectionEvent::Gatt { event } => {
-                match &event {
-                    GattEvent::Read(event) => {
-                        if event.handle() == ssid.handle {
-                            let value = server.get(ssid);
-                            info!("[gatt] Read Event to ssid Characteristic: {:?}", value);
-                        }
+                let mut rejection = None;
+                let mut dfu_status = None;
 
-                        if event.handle() == passwd.handle {
-                            let value = server.get(passwd);
-                            info!("[gatt] Read Event to passwd Characteristic: {:?}", value);
+                match &event {
+                    GattEvent::Write(event)
+                        if [
+                            provisioning.handle,
+                            control.handle,
+                            dfu_control.handle,
+                            dfu_data.handle,
+                        ]
+                        .contains(&event.handle())
+                            && !is_authenticated(conn) =>
+                    {
+                        warn!("[gatt] refusing write over an unauthenticated link");
+                        // kick off pairing from our side, the central will retry afterwards
+                        if let Err(e) = conn.raw().request_security() {
+                            warn!("[gatt] failed to request security: {:?}", e);
                         }
+                        rejection = Some(AttErrorCode::INSUFFICIENT_AUTHENTICATION);
                     }
                     GattEvent::Write(event) => {
-                        if event.handle() == ssid.handle {
-                            info!(
-                                "[gatt] Write Event to ssid Characteristic: {:?}",
-                                event.data()
-                            );
+                        let handle = event.handle();
+
+                        if handle == provisioning.handle {
+                            let message = Chunk::parse(event.data())
+                                .and_then(|chunk| provisioning_chunks.push(chunk));
+
+                            match message {
+                                Ok(Some(message)) => match Provisioning::decode(message) {
+                                    Ok(message) => {
+                                        info!("[gatt] received provisioning message");
+                                        persist_provisioning(config_handle, flash_handle, message)
+                                            .await
+                                    }
+                                    Err(e) => warn!("[gatt] invalid provisioning message: {:?}", e),
+                                },
+                                Ok(None) => {}
+                                Err(e) => warn!("[gatt] dropped provisioning chunk: {:?}", e),
+                            }
+                        }
+
+                        if handle == scan.handle {
+                            info!("[gatt] scan requested");
+                            SCAN_REQUESTED.signal(());
+                        }
+
+                        if handle == control.handle {
+                            match event.data().first().copied().map(ControlOpcode::try_from) {
+                                Some(Ok(ControlOpcode::FactoryReset)) => {
+                                    info!("[gatt] factory reset requested");
+                                    if let Err(e) = factory_reset(config_handle, flash_handle).await
+                                    {
+                                        error!("[gatt] factory reset failed: {}", e);
+                                    }
+                                }
+                                Some(Ok(ControlOpcode::Pair)) => info!("[gatt] paired"),
+                                Some(Ok(ControlOpcode::ConfirmFirmware)) => {
+                                    info!("[gatt] app confirmed the firmware");
+                                    ota::HEALTHY.signal(());
+                                }
+                                other => warn!("[gatt] unknown control opcode: {:?}", other),
+                            }
                         }
 
-                        if event.handle() == passwd.handle {
-                            info!(
-                                "[gatt] Write Event to gh_token Characteristic: {:?}",
-                                event.data()
-                            );
+                        if handle == dfu_control.handle {
+                            let message =
+                                Chunk::parse(event.data()).and_then(|chunk| dfu_chunks.push(chunk));
 
-                            let x = heapless::Vec::from_slice(event.data()).unwrap();
+                            match message {
+                                Ok(Some(message)) => match DfuCommand::decode(message) {
+                                    Ok(command) => dfu_status = dfu.command(command).await,
+                                    Err(e) => warn!("[gatt] invalid DFU command: {:?}", e),
+                                },
+                                Ok(None) => {}
+                                Err(e) => warn!("[gatt] dropped DFU command chunk: {:?}", e),
+                            }
+                        }
 
-                            server.set(passwd, &x).unwrap();
+                        if handle == dfu_data.handle {
+                            dfu_status = dfu.data(event.data()).await;
                         }
                     }
                     _ => {}
                 };
                 // This step is also performed at drop(), but writing it explicitly is necessary
                 // in order to ensure reply is sent.
-                match event.accept() {
+                let reply = match rejection {
+                    Some(code) => event.reject(code),
+                    None => event.accept(),
+                };
+                match reply {
                     Ok(reply) => reply.send().await,
                     Err(e) => warn!("[gatt] error sending response: {:?}", e),
                 };
+
+                if let Some(status) = dfu_status {
+                    notify_dfu_status(dfu_control, conn, status).await?;
+                    if status == DfuStatus::Installed {
+                        info!("[gatt] firmware update installed, restarting");
+                        ota::restart().await;
+                    }
+                }
             }
             _ => {} // ignore other Gatt Connection Events
         }
@@ -160,6 +360,86 @@ async fn gatt_events_task<P: PacketPool>(
     Ok(())
 }
 
+/// Whether the link is encrypted with a key from passkey pairing, rather than Just Works.
+fn is_authenticated<P: PacketPool>(conn: &GattConnection<'_, '_, P>) -> bool {
+    conn.raw()
+        .security_level()
+        .is_ok_and(|level| level.authenticated())
+}
+
+/// Copies a provisioning message into the shared config and commits it to flash,
+/// so the device survives a reboot without being reprovisioned.
+async fn persist_provisioning(
+    config_handle: CapyConfigHandle,
+    flash_handle: CapyFlashHandle,
+    message: Provisioning,
+) {
+    let mut config = config_handle.lock().await;
+    let config = config.get_or_insert_with(CapyConfig::default);
+
+    config.remember_network(message.wifi_credentials);
+    config.api_tokens = message.tokens;
+    if !message.device_name.is_empty() {
+        config.device_name = message.device_name;
+    }
+    if !message.github_user.is_empty() {
+        config.github_user = message.github_user;
+    }
+    if message.refresh_secs != 0 {
+        config.refresh_secs = message.refresh_secs;
+    }
+    if !message.server_url.is_empty() {
+        config.server_url = message.server_url;
+    }
+    if message.full_refresh_every != 0 {
+        config.full_refresh_every = message.full_refresh_every;
+    }
+
+    if let Err(e) = config.write(&mut *flash_handle.lock().await) {
+        error!("[gatt] failed to persist provisioning: {}", e);
+        return;
+    }
+
+    // rejoin even if the credentials didn't change, so the app gets a fresh status to wait on
+    WIFI_CREDENTIALS_CHANGED.signal(());
+}
+
+/// Waits for a factory reset, then drops the central,
+/// sending us back to advertising once the disconnect lands in `gatt_events_task`.
+async fn reset_task<P: PacketPool>(
+    conn: &GattConnection<'_, '_, P>,
+    messages: &mut MessageSubscriber,
+) {
+    loop {
+        if let Message::FactoryReset = messages.next_message_pure().await {
+            break;
+        }
+    }
+
+    info!("[reset] disconnecting");
+    conn.raw().disconnect();
+
+    core::future::pending::<()>().await
+}
+
+/// Notifies the central of the battery charge, whenever the battery monitor measures a new one.
+async fn battery_level_task<P: PacketPool>(
+    server: &Server<'_>,
+    conn: &GattConnection<'_, '_, P>,
+    battery: &mut BatteryReceiver,
+) {
+    let level = &server.battery_service.level;
+    let mut current = battery.get().await;
+
+    loop {
+        if level.notify(conn, &current.percent).await.is_err() {
+            info!("[battery] error notifying connection");
+            break;
+        }
+        current = battery.changed().await;
+    }
+}
+
 /// Create an advertiser to use to connect to a BLE Central, and wait for it to connect.
 async fn advertise<'values, 'server, C: Controller>(
     name: &'values str,
@@ -192,40 +472,77 @@ async fn advertise<'values, 'server, C: Controller>(
     Ok(conn)
 }
 
-/// Example task to use the BLE notifier interface.
-/// This task will notify the connected central of a counter value every 2 seconds.
-/// It will also read the RSSI value every 2 seconds.
-/// and will stop when the connection is closed by the central or an error occurs.
-async fn custom_task<C: Controller, P: PacketPool>(
+/// Notifies the central of every network found by the scans it asks for,
+/// followed by an empty message once a scan is done.
+async fn scan_task<P: PacketPool>(server: &Server<'_>, conn: &GattConnection<'_, '_, P>) {
+    let scan = &server.config_service.scan;
+
+    loop {
+        let networks = SCAN_RESULTS.wait().await;
+        info!("[scan] notifying {} networks", networks.len());
+
+        for network in &networks {
+            let mut buf = [0u8; ScannedNetwork::MAX_ENCODED_LEN];
+            let Ok(message) = network.encode(&mut buf) else {
+                continue;
+            };
+            if notify_chunked(scan, conn, message).await.is_err() {
+                info!("[scan] error notifying connection");
+                return;
+            }
+        }
+
+        if notify_chunked(scan, conn, &[]).await.is_err() {
+            info!("[scan] error notifying connection");
+            return;
+        }
+    }
+}
+
+/// Notifies `message` in chunks small enough for the default MTU.
+async fn notify_chunked<P: PacketPool>(
+    characteristic: &Characteristic<heapless::Vec<u8, MAX_CHUNK_LEN>>,
+    conn: &GattConnection<'_, '_, P>,
+    message: &[u8],
+) -> Result<(), Error> {
+    for chunk in chunks(message) {
+        let mut buf = [0u8; MAX_CHUNK_LEN];
+        let len = chunk.write(&mut buf);
+        // a chunk never exceeds MAX_CHUNK_LEN, so this can't fail
+        let value = heapless::Vec::from_slice(&buf[..len]).unwrap();
+        characteristic.notify(conn, &value).await?;
+    }
+    Ok(())
+}
+
+/// Notifies a DFU status, which always fits in a single write.
+async fn notify_dfu_status<P: PacketPool>(
+    characteristic: &Characteristic<heapless::Vec<u8, MAX_CHUNK_LEN>>,
+    conn: &GattConnection<'_, '_, P>,
+    status: DfuStatus,
+) -> Result<(), Error> {
+    let mut buf = [0u8; DfuStatus::MAX_ENCODED_LEN];
+    // the buffer fits the largest status, and that fits in a chunk's worth
+    let message = status.encode(&mut buf).unwrap();
+    let value = heapless::Vec::from_slice(message).unwrap();
+    characteristic.notify(conn, &value).await
+}
+
+/// Notifies the central of every Wi-Fi status change, starting with the current one.
+async fn status_task<P: PacketPool>(
     server: &Server<'_>,
     conn: &GattConnection<'_, '_, P>,
-    stack: &Stack<'_, C, P>,
+    wifi_status: &mut StatusReceiver,
 ) {
-    let mut tick: u8 = 0;
-    // let level = server.battery_service.level;
-    // let string = &server.string_service.string;
+    let status = &server.config_service.status;
+    let mut current = wifi_status.get().await;
+
     loop {
-        // let slice = string.get(server).unwrap();
-        // let new_string = str::from_utf8(slice.as_slice()).unwrap();
-        // info!("value of string: {}", new_string);
-        tick = tick.wrapping_add(1);
-        info!("[custom_task] notifying connection of tick {}", tick);
-        // if level.notify(conn, &tick).await.is_err() {
-        //     info!("[custom_task] error notifying connection");
-        //     break;
-        // };
-
-        // if string.notify(conn, &slice).await.is_err() {
-        //     info!("[custom_task] error notifying connection");
-        //     break;
-        // }
-        // read RSSI (Received Signal Strength Indicator) of the connection.
-        if let Ok(rssi) = conn.raw().rssi(stack).await {
-            info!("[custom_task] RSSI: {:?}", rssi);
-        } else {
-            info!("[custom_task] error getting RSSI");
+        info!("[status] {:?}", current);
+        if status.notify(conn, &(current as u8)).await.is_err() {
+            info!("[status] error notifying connection");
             break;
-        };
-        Timer::after_secs(2).await;
+        }
+        current = wifi_status.changed().await;
     }
 }
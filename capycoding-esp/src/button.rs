@@ -0,0 +1,60 @@
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::gpio::{Input, InputConfig, Pull};
+use esp_hal::peripherals::GPIO9;
+use log::{error, info};
+
+use crate::{
+    CapyConfigHandle, CapyFlashHandle, Message, PUB_SUB_CHANNEL, PageChange, factory_reset,
+};
+
+/// Presses shorter than this flip to the next page, longer ones go back to the first.
+const LONG_PRESS: Duration = Duration::from_secs(1);
+/// How long the button has to be held down to factory reset the device.
+const RESET_PRESS: Duration = Duration::from_secs(5);
+const DEBOUNCE: Duration = Duration::from_millis(30);
+
+/// Watches the boot button (GPIO9, active low).
+///
+/// A short press shows the next dashboard page, a long press the first one,
+/// holding it for [`RESET_PRESS`] factory resets.
+#[embassy_executor::task]
+pub async fn button_task(
+    pin: GPIO9<'static>,
+    config_handle: CapyConfigHandle,
+    flash_handle: CapyFlashHandle,
+) {
+    info!("Button task started!");
+    let mut button = Input::new(pin, InputConfig::default().with_pull(Pull::Up));
+
+    loop {
+        button.wait_for_falling_edge().await;
+        Timer::after(DEBOUNCE).await;
+        if button.is_high() {
+            // just bounce
+            continue;
+        }
+
+        let pressed = Instant::now();
+        match select(button.wait_for_high(), Timer::after(RESET_PRESS)).await {
+            Either::First(_) => {
+                let change = if pressed.elapsed() < LONG_PRESS {
+                    PageChange::Next
+                } else {
+                    PageChange::Home
+                };
+                info!("[button] {change:?}");
+                PUB_SUB_CHANNEL
+                    .immediate_publisher()
+                    .publish_immediate(Message::Page(change));
+            }
+            Either::Second(_) => {
+                info!("[button] held down, factory resetting");
+                if let Err(e) = factory_reset(config_handle, flash_handle).await {
+                    error!("[button] factory reset failed: {e}");
+                }
+                button.wait_for_high().await;
+            }
+        }
+    }
+}
@@ -1,118 +1,426 @@
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::mutex::Mutex;
+use embassy_time::Duration;
 use embedded_storage::{ReadStorage, nor_flash::NorFlash, nor_flash::check_write};
 use esp_bootloader_esp_idf::partitions::{self, PartitionEntry};
 use esp_storage::FlashStorage;
-use heapless::String;
-use log::{error, info};
+use heapless::{String, Vec};
+use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use static_cell::StaticCell;
+use ble_types::record::{self, HEADER_LEN, RecordError, RecordHeader, Versioned};
 use ble_types::{WifiCredentials, Tokens};
 
+use crate::wifi::WIFI_CREDENTIALS_CHANGED;
+use crate::{Message, PUB_SUB_CHANNEL};
+
+/// How many Wi-Fi networks the device remembers.
+pub const MAX_NETWORKS: usize = 4;
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct CapyConfig {
     pub api_tokens: Tokens,
-    pub wifi_credentials: WifiCredentials,
+    /// Known networks, highest priority first.
+    pub networks: Vec<WifiCredentials, MAX_NETWORKS>,
+    pub device_name: String<30>,
+    pub github_user: String<39>,
+    /// Seconds between metrics refreshes, zero means [`DEFAULT_REFRESH_SECS`].
+    pub refresh_secs: u16,
+    /// Empty means [`DEFAULT_SERVER_URL`].
+    pub server_url: String<96>,
+    /// Fast display updates between full refreshes, zero means [`DEFAULT_FULL_REFRESH_EVERY`].
+    pub full_refresh_every: u8,
+}
+
+pub const DEFAULT_REFRESH_SECS: u16 = 60;
+pub const DEFAULT_FULL_REFRESH_EVERY: u8 = 20;
+pub const DEFAULT_SERVER_URL: &str = "https://cappycoding.koyeb.app";
+
+/// `Tokens` as stored up to v4, before it had a server token.
+type TokensV4 = (String<100>,);
+
+fn upgrade_tokens((github,): TokensV4) -> Tokens {
+    Tokens {
+        github,
+        ..Default::default()
+    }
 }
 
+/// Big enough for the record header plus a postcard encoded `CapyConfig`.
+const RECORD_BUF_LEN: usize = HEADER_LEN + size_of::<CapyConfig>();
+
+impl Versioned for CapyConfig {
+    /// Bump this when the layout of `CapyConfig` (or anything it contains) changes,
+    /// and implement `migrate` so it can read the previous layout.
+    const VERSION: u16 = 6;
+
+    fn migrate(version: u16, payload: &[u8]) -> Result<Self, RecordError> {
+        match version {
+            // v1 predates the device name
+            1 => {
+                let (api_tokens, wifi_credentials): (TokensV4, WifiCredentials) =
+                    postcard::from_bytes(payload).map_err(|_| RecordError::Deserialize)?;
+                let mut config = Self {
+                    api_tokens: upgrade_tokens(api_tokens),
+                    ..Default::default()
+                };
+                config.remember_network(wifi_credentials);
+                Ok(config)
+            }
+            // v2 only knew a single network
+            2 => {
+                let (api_tokens, wifi_credentials, device_name): (
+                    TokensV4,
+                    WifiCredentials,
+                    String<30>,
+                ) = postcard::from_bytes(payload).map_err(|_| RecordError::Deserialize)?;
+                let mut config = Self {
+                    api_tokens: upgrade_tokens(api_tokens),
+                    device_name,
+                    ..Default::default()
+                };
+                config.remember_network(wifi_credentials);
+                Ok(config)
+            }
+            // v3 predates the metrics settings
+            3 => {
+                let (api_tokens, networks, device_name): (
+                    TokensV4,
+                    Vec<WifiCredentials, MAX_NETWORKS>,
+                    String<30>,
+                ) = postcard::from_bytes(payload).map_err(|_| RecordError::Deserialize)?;
+                Ok(Self {
+                    api_tokens: upgrade_tokens(api_tokens),
+                    networks,
+                    device_name,
+                    ..Default::default()
+                })
+            }
+            // v4 always talked to the default server, without a token
+            4 => {
+                let (api_tokens, networks, device_name, github_user, refresh_secs): (
+                    TokensV4,
+                    Vec<WifiCredentials, MAX_NETWORKS>,
+                    String<30>,
+                    String<39>,
+                    u16,
+                ) = postcard::from_bytes(payload).map_err(|_| RecordError::Deserialize)?;
+                Ok(Self {
+                    api_tokens: upgrade_tokens(api_tokens),
+                    networks,
+                    device_name,
+                    github_user,
+                    refresh_secs,
+                    ..Default::default()
+                })
+            }
+            // v5 left the display refresh cadence up to the firmware
+            5 => {
+                let (api_tokens, networks, device_name, github_user, refresh_secs, server_url): (
+                    Tokens,
+                    Vec<WifiCredentials, MAX_NETWORKS>,
+                    String<30>,
+                    String<39>,
+                    u16,
+                    String<96>,
+                ) = postcard::from_bytes(payload).map_err(|_| RecordError::Deserialize)?;
+                Ok(Self {
+                    api_tokens,
+                    networks,
+                    device_name,
+                    github_user,
+                    refresh_secs,
+                    server_url,
+                    ..Default::default()
+                })
+            }
+            v => Err(RecordError::UnsupportedVersion(v)),
+        }
+    }
+}
 
 pub type CapyConfigHandle = &'static Mutex<CriticalSectionRawMutex, Option<CapyConfig>>;
+
+/// Flash is needed by whoever persists config, so it lives behind a shared mutex.
+pub type CapyFlashHandle = &'static Mutex<CriticalSectionRawMutex, FlashStorage<'static>>;
+
+/// Config is double buffered across two flash sectors. Each write goes to the slot
+/// not holding the newest record, so a brownout mid-write leaves the previous config intact
+/// and the wear is spread over both sectors.
+const SECTOR_SIZE: u32 = 0x1000;
+const SLOT_OFFSETS: [u32; 2] = [0, SECTOR_SIZE];
+
+/// Everything that can go wrong while persisting config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The partition table couldn't be read.
+    PartitionTable,
+    /// There is no NVS partition to store config in.
+    PartitionMissing,
+    /// Reading, erasing or writing the flash failed.
+    Flash,
+    /// The config doesn't fit in a record.
+    Overflow,
+    /// Every slot holds a record, but none of them are valid.
+    Corrupt(RecordError),
+}
+
+impl core::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ConfigError::PartitionTable => write!(f, "Bad partition table"),
+            ConfigError::PartitionMissing => write!(f, "No NVS partition"),
+            ConfigError::Flash => write!(f, "Flash I/O failed"),
+            ConfigError::Overflow => write!(f, "Config too large"),
+            ConfigError::Corrupt(e) => write!(f, "Config corrupt ({e:?})"),
+        }
+    }
+}
+
+/// A valid record read back from one of the slots.
+struct StoredConfig {
+    slot: usize,
+    header: RecordHeader,
+    config: CapyConfig,
+}
+
 impl CapyConfig {
-    pub fn load(flash: &mut FlashStorage<'static>) -> Option<Self> {
+    pub fn refresh_interval(&self) -> Duration {
+        let secs = match self.refresh_secs {
+            0 => DEFAULT_REFRESH_SECS,
+            secs => secs,
+        };
+        Duration::from_secs(secs.into())
+    }
+
+    /// How many fast display updates may happen before a full refresh clears the ghosting.
+    pub fn full_refresh_every(&self) -> u8 {
+        match self.full_refresh_every {
+            0 => DEFAULT_FULL_REFRESH_EVERY,
+            every => every,
+        }
+    }
+
+    /// The server to fetch metrics from, without a trailing slash.
+    pub fn server_url(&self) -> &str {
+        match self.server_url.trim_end_matches('/') {
+            "" => DEFAULT_SERVER_URL,
+            url => url,
+        }
+    }
+
+    /// Puts `credentials` at the top of the known networks, replacing any older entry
+    /// for the same SSID and forgetting the lowest priority network if the list is full.
+    pub fn remember_network(&mut self, credentials: WifiCredentials) {
+        if credentials.ssid.is_empty() {
+            return;
+        }
+
+        self.networks.retain(|known| known.ssid != credentials.ssid);
+        if self.networks.is_full() {
+            self.networks.pop();
+        }
+        // there is room now, we just made sure of it
+        let _ = self.networks.insert(0, credentials);
+    }
+
+    /// Loads the newest valid config, `Ok(None)` means nothing has been provisioned yet.
+    pub fn load(flash: &mut FlashStorage<'static>) -> Result<Option<Self>, ConfigError> {
         let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
-        let nvs_entry = get_nvs_partition(flash, &mut pt_mem);
+        let nvs_entry = get_nvs_partition(flash, &mut pt_mem)?;
         let mut nvs = nvs_entry.as_embedded_storage(flash);
-        let nvs_read_offset = 0;
-        let mut raw_config_buf = [0u8; size_of::<CapyConfig>()];
-        nvs.read(nvs_read_offset, &mut raw_config_buf).unwrap();
 
-        // Check if flash is empty (uninitialized)
-        let is_empty = raw_config_buf.iter().all(|&b| b == 0xFF || b == 0x00);
+        let corrupt = match read_newest(&mut nvs)? {
+            Ok(Some(newest)) => {
+                info!(
+                    "Successfully loaded CapyState from slot {} (seq {})",
+                    newest.slot, newest.header.sequence
+                );
+                return Ok(Some(newest.config));
+            }
+            Ok(None) => None,
+            Err(e) => Some(e),
+        };
 
-        if is_empty {
-            info!("Flash is empty, returning default CapyState");
-            // return CapyConfig::default();
-            return None;
+        // configs written before the record format existed were bare v1 postcard at offset 0,
+        // which blank flash and most garbage decode as too, so only take one with a network
+        let mut raw_config_buf = [0u8; RECORD_BUF_LEN];
+        read_slot(&mut nvs, SLOT_OFFSETS[0], &mut raw_config_buf)?;
+        if !record::is_blank(&raw_config_buf)
+            && let Err(RecordError::BadMagic) = record::decode::<CapyConfig>(&raw_config_buf)
+            && let Ok(legacy) = CapyConfig::migrate(1, &raw_config_buf)
+            // remember_network skips an empty SSID
+            && !legacy.networks.is_empty()
+        {
+            info!("Loaded legacy CapyState, it will be upgraded on next write");
+            return Ok(Some(legacy));
         }
 
-        match postcard::from_bytes(&raw_config_buf) {
-            Ok(read_from_flash) => {
-                info!("Successfully loaded CapyState from flash");
-                Some(read_from_flash)
-            }
-            Err(e) => {
-                error!("Error deserializing from flash: {e}, returning default");
-                // CapyConfig::default()
-                None
+        match corrupt {
+            Some(e) => Err(ConfigError::Corrupt(e)),
+            None => {
+                info!("No CapyState stored in flash");
+                Ok(None)
             }
         }
     }
 
-    pub fn write(&self, flash: &mut FlashStorage<'static>) {
+    pub fn write(&self, flash: &mut FlashStorage<'static>) -> Result<(), ConfigError> {
         let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
-        let nvs_entry = get_nvs_partition(flash, &mut pt_mem);
+        let nvs_entry = get_nvs_partition(flash, &mut pt_mem)?;
         let mut nvs = nvs_entry.as_embedded_storage(flash);
-        let nvs_write_offset = 0;
 
-        // Serialize self to buffer
-        let mut raw_config_buf = [0u8; size_of::<CapyConfig>()];
-        let serialized = postcard::to_slice(self, &mut raw_config_buf).unwrap();
+        // write over whichever slot is stale, never the newest one
+        let (slot, sequence) = match read_newest(&mut nvs)? {
+            Ok(Some(newest)) => (
+                (newest.slot + 1) % SLOT_OFFSETS.len(),
+                newest.header.sequence.wrapping_add(1),
+            ),
+            // nothing worth keeping, start over
+            Ok(None) | Err(_) => (0, 0),
+        };
+        let nvs_write_offset = SLOT_OFFSETS[slot];
+
+        // Serialize self to buffer, padding is left erased
+        let mut raw_config_buf = [0xFFu8; RECORD_BUF_LEN + 3];
+        let serialized = record::encode(self, sequence, &mut raw_config_buf)
+            .map_err(|_| ConfigError::Overflow)?;
 
         // Align the write length to word boundary (4 bytes for ESP32)
         let aligned_len = (serialized.len() + 3) & !3;
 
         // Erase before writing - REQUIRED for flash
-        let erase_size = (aligned_len + 0xFFF) & !0xFFF; // Round up to 4KB sector
-        nvs.erase(nvs_write_offset, erase_size as u32)
-            .map_err(|e| error!("Failed to erase flash! {:?}", e))
-            .unwrap();
+        nvs.erase(nvs_write_offset, nvs_write_offset + SECTOR_SIZE)
+            .map_err(|e| {
+                error!("Failed to erase flash! {:?}", e);
+                ConfigError::Flash
+            })?;
 
-        check_write(&nvs, nvs_write_offset, aligned_len).unwrap();
+        check_write(&nvs, nvs_write_offset, aligned_len).map_err(|e| {
+            error!("Refusing to write flash! {:?}", e);
+            ConfigError::Flash
+        })?;
         nvs.write(nvs_write_offset, &raw_config_buf[..aligned_len])
-            .map_err(|e| error!("Failed to write flash! {e}"))
-            .unwrap();
+            .map_err(|e| {
+                error!("Failed to write flash! {e}");
+                ConfigError::Flash
+            })?;
 
         info!(
-            "Successfully wrote CapyState to flash ({} bytes)",
-            aligned_len
+            "Successfully wrote CapyState to slot {} (seq {}, {} bytes)",
+            slot, sequence, aligned_len
         );
+        Ok(())
     }
 
-    pub fn erase(&self, flash: &mut FlashStorage<'static>) {
+    pub fn erase(flash: &mut FlashStorage<'static>) -> Result<(), ConfigError> {
         let mut pt_mem = [0u8; partitions::PARTITION_TABLE_MAX_LEN];
-        let nvs_entry = get_nvs_partition(flash, &mut pt_mem);
+        let nvs_entry = get_nvs_partition(flash, &mut pt_mem)?;
         let mut nvs = nvs_entry.as_embedded_storage(flash);
-        let nvs_write_offset = 0;
 
-        // Serialize self to buffer
-        let mut raw_config_buf = [0u8; size_of::<CapyConfig>()];
-        let serialized = postcard::to_slice(self, &mut raw_config_buf).unwrap();
+        // wipe both slots so neither record can be picked up on the next load
+        let slots_end = SLOT_OFFSETS[SLOT_OFFSETS.len() - 1] + SECTOR_SIZE;
+        nvs.erase(SLOT_OFFSETS[0], slots_end).map_err(|e| {
+            error!("Failed to erase flash! {:?}", e);
+            ConfigError::Flash
+        })
+    }
+}
 
-        // Align the write length to word boundary (4 bytes for ESP32)
-        let aligned_len = (serialized.len() + 3) & !3;
+/// Wipes the stored config and forgets the in-memory copy, leaving the device unprovisioned.
+pub async fn factory_reset(
+    config_handle: CapyConfigHandle,
+    flash_handle: CapyFlashHandle,
+) -> Result<(), ConfigError> {
+    CapyConfig::erase(&mut *flash_handle.lock().await)?;
+    *config_handle.lock().await = None;
 
-        // Erase before writing - REQUIRED for flash
-        let erase_size = (aligned_len + 0xFFF) & !0xFFF; // Round up to 4KB sector
-        nvs.erase(nvs_write_offset, erase_size as u32)
-            .map_err(|e| error!("Failed to erase flash! {:?}", e))
-            .unwrap();
+    // drop off the old network and let everyone else know
+    WIFI_CREDENTIALS_CHANGED.signal(());
+    PUB_SUB_CHANNEL
+        .immediate_publisher()
+        .publish_immediate(Message::FactoryReset);
+
+    info!("Factory reset complete");
+    Ok(())
+}
+
+fn read_slot<S: ReadStorage>(nvs: &mut S, offset: u32, buf: &mut [u8]) -> Result<(), ConfigError>
+where
+    S::Error: core::fmt::Debug,
+{
+    nvs.read(offset, buf).map_err(|e| {
+        error!("Failed to read flash! {:?}", e);
+        ConfigError::Flash
+    })
+}
+
+/// Reads every slot and returns the valid record with the highest sequence number.
+///
+/// Erased slots are skipped. If no slot is valid but at least one holds a broken record,
+/// that record's error is returned so callers can tell corruption apart from a blank device.
+fn read_newest<S: ReadStorage>(
+    nvs: &mut S,
+) -> Result<Result<Option<StoredConfig>, RecordError>, ConfigError>
+where
+    S::Error: core::fmt::Debug,
+{
+    let mut newest: Option<StoredConfig> = None;
+    let mut corrupt = None;
+
+    for (slot, &offset) in SLOT_OFFSETS.iter().enumerate() {
+        let mut raw_config_buf = [0u8; RECORD_BUF_LEN];
+        read_slot(nvs, offset, &mut raw_config_buf)?;
+
+        let (header, config) = match record::decode::<CapyConfig>(&raw_config_buf) {
+            Ok(read) => read,
+            Err(RecordError::Empty) => continue,
+            Err(e) => {
+                warn!("Ignoring config slot {slot}: {e:?}");
+                corrupt = Some(e);
+                continue;
+            }
+        };
+
+        if newest
+            .as_ref()
+            .is_none_or(|current| header.is_newer_than(&current.header))
+        {
+            newest = Some(StoredConfig {
+                slot,
+                header,
+                config,
+            });
+        }
     }
+
+    Ok(match (newest, corrupt) {
+        (None, Some(e)) => Err(e),
+        (newest, _) => Ok(newest),
+    })
 }
 
 fn get_nvs_partition<'a>(
     flash: &mut FlashStorage<'static>,
     pt_mem: &'a mut [u8; partitions::PARTITION_TABLE_MAX_LEN],
-) -> PartitionEntry<'a> {
-    let pt = partitions::read_partition_table(flash, pt_mem).unwrap();
+) -> Result<PartitionEntry<'a>, ConfigError> {
+    let pt = partitions::read_partition_table(flash, pt_mem).map_err(|e| {
+        error!("Failed to read partition table! {:?}", e);
+        ConfigError::PartitionTable
+    })?;
 
     for i in 0..pt.len() {
-        let raw = pt.get_partition(i).unwrap();
-        info!("pt i:{i}, value: {raw:?}");
+        if let Ok(raw) = pt.get_partition(i) {
+            info!("pt i:{i}, value: {raw:?}");
+        }
     }
 
     pt.find_partition(partitions::PartitionType::Data(
         partitions::DataPartitionSubType::Nvs,
     ))
-    .unwrap()
-    .unwrap()
+    .map_err(|e| {
+        error!("Failed to search partition table! {:?}", e);
+        ConfigError::PartitionTable
+    })?
+    .ok_or(ConfigError::PartitionMissing)
 }
@@ -6,6 +6,14 @@ pub use term::*;
 mod config;
 pub use config::*;
 
+mod message;
+pub use message::*;
+
+pub mod battery;
+pub mod button;
+pub mod ota;
+pub mod power;
+
 pub mod ble;
 pub mod wifi;
 
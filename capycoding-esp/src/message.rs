@@ -0,0 +1,46 @@
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use heapless::Vec;
+
+pub use capycoding_ui::PageChange;
+
+use crate::battery::Battery;
+
+use crate::wifi::api::{ClaudeMetrics, CommitMetrics, MAX_ITEMS, PullRequest, WorkflowRun};
+
+/// Events broadcast between tasks.
+#[derive(Clone, Debug)]
+pub enum Message {
+    /// A central connected over BLE.
+    Connected,
+    /// The central went away again.
+    Disconnected,
+    /// The stored config was wiped and the device is unprovisioned again.
+    FactoryReset,
+    /// A central is pairing and the user has to type this passkey on their computer.
+    PairingPasskey(u32),
+    /// Pairing finished, successfully or not.
+    PairingDone,
+    /// Metrics fetched from the server, only sent when they differ from the last ones.
+    /// `None` means nobody has pushed Claude metrics to the server yet.
+    ClaudeMetrics(Option<ClaudeMetrics>),
+    PullRequests(Vec<PullRequest, MAX_ITEMS>),
+    WorkflowRuns(Vec<WorkflowRun, MAX_ITEMS>),
+    Commits(CommitMetrics),
+    /// Whether the server's certificate is currently failing verification.
+    ServerUntrusted(bool),
+    /// The user pressed the button to flip through the dashboard.
+    Page(PageChange),
+    /// The battery charge changed.
+    Battery(Battery),
+    /// How far a firmware update got in percent, `None` once it failed.
+    FirmwareUpdate(Option<u8>),
+    /// The device is about to deep sleep, the UI signals `power::DISPLAY_SETTLED` once
+    /// everything published before this is on screen.
+    Sleep,
+}
+
+/// Subscribers are the UI, BLE and power tasks; publish with `immediate_publisher` so
+/// any task can send without claiming a publisher slot.
+pub static PUB_SUB_CHANNEL: PubSubChannel<CriticalSectionRawMutex, Message, 20, 3, 1> =
+    PubSubChannel::new();
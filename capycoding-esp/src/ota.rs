@@ -0,0 +1,338 @@
+//! Over the air firmware updates, fetched from the server or pushed over BLE.
+//!
+//! Uses the esp-idf OTA layout from `partitions.csv`: the running image sits in one app
+//! slot, an [`Update`] is written to the other one and checked against its SHA-256 before
+//! the bootloader is pointed at it. That SHA-256 has to be signed with the release key
+//! built in as `CAPYCODING_RELEASE_KEY`, see [`verify_signature`], so neither whoever
+//! runs the server nor a paired central can install an image that wasn't released.
+//! Builds without a release key refuse every update.
+//!
+//! A freshly updated image boots as [`OtaImageState::New`] and has [`HEALTH_TIMEOUT`] to
+//! reach the server or be confirmed by the app over an authenticated link. If it is, it's
+//! marked valid, otherwise it marks itself invalid and resets, and the bootloader goes back
+//! to the previous image. Until then the device stays awake, see [`VERIFYING`]: waking
+//! from deep sleep is a reset, which the bootloader would count as a failed boot.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use ed25519_dalek::{Signature, VerifyingKey};
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Timer};
+use embedded_storage::{ReadStorage, Storage};
+use esp_bootloader_esp_idf::ota::OtaImageState;
+use esp_bootloader_esp_idf::ota_updater::OtaUpdater;
+use esp_bootloader_esp_idf::partitions::{self, PARTITION_TABLE_MAX_LEN};
+use esp_hal::system::software_reset;
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+
+use crate::wifi::api::{Api, ApiError};
+use crate::{CapyFlashHandle, Message, PUB_SUB_CHANNEL};
+
+/// Version of the running firmware, releases are compared against it.
+pub const CURRENT_VERSION: &str = env!("CARGO_PKG_VERSION");
+/// Public half of the key releases are signed with, as 64 hex digits.
+const RELEASE_KEY: Option<&str> = option_env!("CAPYCODING_RELEASE_KEY");
+/// How long a new image gets to prove itself before it's rolled back.
+const HEALTH_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// How long the screen shows a finished update before restarting into it.
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Signalled once the server answered or the paired app confirmed the image, proving it works.
+pub static HEALTHY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether the running image still waits to be marked valid or invalid, the power task
+/// doesn't sleep while it does. Starts out set, the image state isn't known before
+/// [`ota_health_task`] read it. Only the power task receives it.
+pub static VERIFYING: Watch<CriticalSectionRawMutex, bool, 1> = Watch::new_with(true);
+
+/// Set while an [`Update`] exists, so the server and a central can't write the slot at once.
+static IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum OtaError {
+    Api(ApiError),
+    /// Reading the partition table, the OTA data or writing the image failed.
+    Flash(partitions::Error),
+    /// Writing the image to the inactive slot failed.
+    Write,
+    /// The image doesn't fit in an app slot, or more of it arrived than announced.
+    TooLarge(u32),
+    /// The image ended before all of it arrived.
+    Incomplete,
+    /// The image doesn't hash to what was announced.
+    HashMismatch,
+    /// The server published a hash that isn't 64 hex digits.
+    InvalidHash,
+    /// The server published a signature that isn't 128 hex digits.
+    InvalidSignature,
+    /// This build has no valid `CAPYCODING_RELEASE_KEY` to check signatures against.
+    NoReleaseKey,
+    /// The image's hash isn't signed with the release key.
+    BadSignature,
+    /// Another update is already being written.
+    Busy,
+}
+
+impl From<ApiError> for OtaError {
+    fn from(e: ApiError) -> Self {
+        OtaError::Api(e)
+    }
+}
+
+impl From<partitions::Error> for OtaError {
+    fn from(e: partitions::Error) -> Self {
+        OtaError::Flash(e)
+    }
+}
+
+/// An image being written to the inactive slot, piece by piece.
+///
+/// Its hash has to pass [`verify_signature`] before it's begun. Dropping it before
+/// [`Update::install`] worked out abandons the update.
+pub struct Update {
+    flash_handle: CapyFlashHandle,
+    size: u32,
+    sha256: [u8; 32],
+    hasher: Sha256,
+    written: u32,
+    last_percent: u8,
+    installed: bool,
+}
+
+impl Update {
+    /// Starts writing an image of `size` bytes, which has to hash to `sha256`.
+    pub async fn begin(
+        flash_handle: CapyFlashHandle,
+        size: u32,
+        sha256: [u8; 32],
+    ) -> Result<Self, OtaError> {
+        if IN_PROGRESS.swap(true, Ordering::AcqRel) {
+            return Err(OtaError::Busy);
+        }
+        // from here on dropping it clears `IN_PROGRESS` again
+        let update = Self {
+            flash_handle,
+            size,
+            sha256,
+            hasher: Sha256::new(),
+            written: 0,
+            last_percent: 0,
+            installed: false,
+        };
+
+        {
+            let mut flash = flash_handle.lock().await;
+            let mut pt_mem = [0u8; PARTITION_TABLE_MAX_LEN];
+            let mut ota = OtaUpdater::new(&mut *flash, &mut pt_mem)?;
+            let (slot, _) = ota.next_partition()?;
+            if size as usize > slot.capacity() {
+                return Err(OtaError::TooLarge(size));
+            }
+        }
+
+        publish_progress(Some(0));
+        Ok(update)
+    }
+
+    /// Size of the whole image in bytes.
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    /// Bytes of the image written so far.
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    /// Appends the next `piece` of the image.
+    pub async fn write(&mut self, piece: &[u8]) -> Result<(), OtaError> {
+        let end = self.written as u64 + piece.len() as u64;
+        if end > u64::from(self.size) {
+            return Err(OtaError::TooLarge(end as u32));
+        }
+        self.hasher.update(piece);
+
+        // only hold the flash while writing, the config may want it between pieces
+        {
+            let mut flash = self.flash_handle.lock().await;
+            let mut pt_mem = [0u8; PARTITION_TABLE_MAX_LEN];
+            let mut ota = OtaUpdater::new(&mut *flash, &mut pt_mem)?;
+            let (mut slot, _) = ota.next_partition()?;
+            slot.write(self.written, piece).map_err(|e| {
+                error!("[ota] failed to write at {}: {e:?}", self.written);
+                OtaError::Write
+            })?;
+        }
+        self.written = end as u32;
+
+        // every step of 10% is a redraw, that's plenty for an e-paper
+        let percent = (end * 100 / u64::from(self.size)) as u8;
+        if percent / 10 > self.last_percent / 10 {
+            self.last_percent = percent;
+            publish_progress(Some(percent));
+        }
+        Ok(())
+    }
+
+    /// Checks the image is complete and intact, then points the bootloader at it.
+    /// The new image only runs after a [`restart`].
+    pub async fn install(mut self) -> Result<(), OtaError> {
+        if self.written != self.size {
+            return Err(OtaError::Incomplete);
+        }
+        let digest: [u8; 32] = self.hasher.clone().finalize().into();
+        if digest != self.sha256 {
+            return Err(OtaError::HashMismatch);
+        }
+
+        let mut flash = self.flash_handle.lock().await;
+        let mut pt_mem = [0u8; PARTITION_TABLE_MAX_LEN];
+        let mut ota = OtaUpdater::new(&mut *flash, &mut pt_mem)?;
+        ota.activate_next_partition()?;
+        ota.set_current_ota_state(OtaImageState::New)?;
+
+        info!("[ota] {} byte image installed", self.size);
+        self.installed = true;
+        Ok(())
+    }
+}
+
+impl Drop for Update {
+    fn drop(&mut self) {
+        // an installed update keeps others out until the restart
+        if !self.installed {
+            publish_progress(None);
+            IN_PROGRESS.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// Gives the screen a moment to show the update finished, then restarts into it.
+pub async fn restart() -> ! {
+    Timer::after(RESTART_DELAY).await;
+    software_reset()
+}
+
+/// Installs the server's release if it's newer than the running firmware.
+///
+/// Returns without an update, doesn't return at all after one: the device restarts into it.
+pub async fn update(api: &Api<'_>, flash_handle: CapyFlashHandle) -> Result<(), OtaError> {
+    let Some(release) = api.firmware_release().await? else {
+        return Ok(());
+    };
+    if !newer(&release.version, CURRENT_VERSION) {
+        return Ok(());
+    }
+    info!(
+        "[ota] updating from {CURRENT_VERSION} to {}, {} bytes",
+        release.version, release.size
+    );
+
+    let sha256 = parse_hex(&release.sha256).ok_or(OtaError::InvalidHash)?;
+    let signature = parse_hex(&release.signature).ok_or(OtaError::InvalidSignature)?;
+    verify_signature(&sha256, &signature)?;
+    let mut update = Update::begin(flash_handle, release.size, sha256).await?;
+    api.firmware_image(release.size, async |piece| update.write(piece).await)
+        .await?;
+    update.install().await?;
+    restart().await
+}
+
+/// Checks `signature` is the release key's signature of an image's `sha256`.
+pub fn verify_signature(sha256: &[u8; 32], signature: &[u8; 64]) -> Result<(), OtaError> {
+    let key = RELEASE_KEY
+        .and_then(parse_hex)
+        .and_then(|key| VerifyingKey::from_bytes(&key).ok())
+        .ok_or(OtaError::NoReleaseKey)?;
+    key.verify_strict(sha256, &Signature::from_bytes(signature))
+        .map_err(|_| OtaError::BadSignature)
+}
+
+/// Confirms a freshly updated image once it proved it works, rolls it back if it can't.
+#[embassy_executor::task]
+pub async fn ota_health_task(flash_handle: CapyFlashHandle) {
+    let state = {
+        let mut flash = flash_handle.lock().await;
+        let mut pt_mem = [0u8; PARTITION_TABLE_MAX_LEN];
+        OtaUpdater::new(&mut *flash, &mut pt_mem).and_then(|mut ota| ota.current_ota_state())
+    };
+    match state {
+        Ok(OtaImageState::New | OtaImageState::PendingVerify) => {}
+        // nothing to confirm, or flashed over USB without the OTA partitions
+        _ => {
+            VERIFYING.sender().send(false);
+            return;
+        }
+    }
+
+    info!("[ota] running a new image, waiting for it to prove itself");
+    let healthy = matches!(
+        select(HEALTHY.wait(), Timer::after(HEALTH_TIMEOUT)).await,
+        Either::First(_)
+    );
+    let verdict = if healthy {
+        OtaImageState::Valid
+    } else {
+        OtaImageState::Invalid
+    };
+
+    let result = {
+        let mut flash = flash_handle.lock().await;
+        let mut pt_mem = [0u8; PARTITION_TABLE_MAX_LEN];
+        OtaUpdater::new(&mut *flash, &mut pt_mem)
+            .and_then(|mut ota| ota.set_current_ota_state(verdict))
+    };
+    if let Err(e) = result {
+        error!("[ota] failed to mark the image {verdict:?}: {e:?}");
+    }
+
+    if healthy {
+        info!("[ota] new image confirmed");
+        VERIFYING.sender().send(false);
+    } else {
+        // the bootloader skips invalid images, so this boots the previous one
+        warn!("[ota] new image was never confirmed, rolling back");
+        software_reset();
+    }
+}
+
+/// Whether `candidate` is a higher `major.minor.patch` than `current`.
+///
+/// Anything that doesn't parse never counts as newer, so a typo can't start an update.
+fn newer(candidate: &str, current: &str) -> bool {
+    fn parse(version: &str) -> Option<(u32, u32, u32)> {
+        // pre-release and build suffixes aren't worth telling apart
+        let core = version.split(['-', '+']).next()?;
+        let mut parts = core.split('.').map(|part| part.parse().ok());
+        let version = (parts.next()??, parts.next()??, parts.next()??);
+        parts.next().is_none().then_some(version)
+    }
+
+    match (parse(candidate), parse(current)) {
+        (Some(candidate), Some(current)) => candidate > current,
+        _ => false,
+    }
+}
+
+/// Decodes exactly `N` bytes in hex, as the server publishes hashes and signatures.
+fn parse_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != 2 * N {
+        return None;
+    }
+    let mut bytes = [0u8; N];
+    for (byte, pair) in bytes.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(core::str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+/// Shows how far the update got, `None` once it failed.
+fn publish_progress(percent: Option<u8>) {
+    PUB_SUB_CHANNEL
+        .immediate_publisher()
+        .publish_immediate(Message::FirmwareUpdate(percent));
+}
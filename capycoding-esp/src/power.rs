@@ -0,0 +1,151 @@
+//! Deep sleep between metrics refreshes, for the battery powered build.
+//!
+//! The e-paper keeps its image without power, so once the metrics are refreshed and drawn
+//! there's nothing to stay awake for until the next refresh is due. Waking up from deep
+//! sleep is a full reboot, [`woke_from_sleep`] tells it apart from a power on.
+//!
+//! After a power on or reset the device stays awake for [`PROVISIONING_WINDOW`], advertising
+//! over BLE so the app can find it. Unprovisioned devices and devices with a central
+//! connected never sleep, and neither do devices downloading a firmware update or running
+//! a new one that isn't confirmed yet.
+//!
+//! Only the RTC timer wakes the device up. The ESP32-C3 can only wake from deep sleep on
+//! GPIO0 to GPIO5, which the display uses, not on the button's GPIO9.
+
+use embassy_futures::select::{Either4, select4};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::Watch;
+use embassy_time::{Duration, Instant, Timer, with_timeout};
+use esp_hal::peripherals::LPWR;
+use esp_hal::rtc_cntl::sleep::TimerWakeupSource;
+use esp_hal::rtc_cntl::{Rtc, SleepSource, wakeup_cause};
+use log::{info, warn};
+
+use crate::{CapyConfigHandle, DEFAULT_REFRESH_SECS, Message, PUB_SUB_CHANNEL, ota};
+
+/// How long a freshly powered on device advertises and stays awake.
+pub const PROVISIONING_WINDOW: Duration = Duration::from_secs(120);
+/// How long the device stays awake after a button press, for flipping through the pages.
+const BUTTON_GRACE: Duration = Duration::from_secs(30);
+/// How long a refresh may be overdue, say without a network, before sleeping regardless.
+const WAKE_TIMEOUT: Duration = Duration::from_secs(60);
+/// Sleeping any shorter than this costs more than the boot it takes to wake up.
+const MIN_SLEEP: Duration = Duration::from_secs(10);
+/// How long a device with a low battery sleeps, regardless of the refresh interval.
+const LOW_BATTERY_SLEEP: Duration = Duration::from_secs(60 * 60);
+/// Longest we wait for the display to finish before going to sleep.
+const DISPLAY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Signalled by the metrics refresh after every attempt, with the delay until the next one.
+pub static REFRESHED: Signal<CriticalSectionRawMutex, Duration> = Signal::new();
+
+/// Signalled by `ui_task` once everything published before [`Message::Sleep`] is on screen.
+pub static DISPLAY_SETTLED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Whether the BLE task should advertise, only the BLE task receives it.
+pub static ADVERTISE: Watch<CriticalSectionRawMutex, bool, 1> = Watch::new_with(true);
+
+/// Woken from deep sleep by the timer, rather than powered on or reset.
+pub fn woke_from_sleep() -> bool {
+    matches!(wakeup_cause(), SleepSource::Timer)
+}
+
+/// Sends the device to deep sleep whenever nobody needs it awake.
+#[embassy_executor::task]
+pub async fn power_task(lpwr: LPWR<'static>, config_handle: CapyConfigHandle) {
+    info!("Power task started!");
+    let mut rtc = Rtc::new(lpwr);
+    let mut messages = PUB_SUB_CHANNEL.subscriber().unwrap();
+    let mut verifying_receiver = ota::VERIFYING.receiver().unwrap();
+
+    let boot = Instant::now();
+    let timer_wake = woke_from_sleep();
+    let provisioning_until = if timer_wake {
+        boot
+    } else {
+        boot + PROVISIONING_WINDOW
+    };
+    let mut awake_until = provisioning_until;
+    let mut ble_connected = false;
+    let mut battery_low = false;
+    let mut updating = false;
+    // a reset before the new image is confirmed rolls it back
+    let mut verifying = true;
+    // the first refresh is due as soon as we're up
+    let mut next_refresh = boot;
+
+    loop {
+        let now = Instant::now();
+        let provisioned = config_handle.lock().await.is_some();
+
+        let advertise = !provisioned || now < provisioning_until;
+        if ADVERTISE.try_get() != Some(advertise) {
+            ADVERTISE.sender().send(advertise);
+        }
+
+        if provisioned && !ble_connected && !updating && !verifying && now >= awake_until {
+            let until_due = next_refresh.saturating_duration_since(now);
+            if battery_low {
+                // refreshing would only drain it faster, the screen asks to be charged
+                sleep(&mut rtc, until_due.max(LOW_BATTERY_SLEEP)).await;
+            }
+            if until_due >= MIN_SLEEP {
+                sleep(&mut rtc, until_due).await;
+            }
+            if now >= next_refresh + WAKE_TIMEOUT {
+                warn!("Refresh overdue, sleeping until the next one");
+                sleep(&mut rtc, Duration::from_secs(DEFAULT_REFRESH_SECS.into())).await;
+            }
+            // due soon, stay up for it
+        }
+
+        // the next point in time the decisions above could turn out differently
+        let wake_at = [provisioning_until, awake_until, next_refresh + WAKE_TIMEOUT]
+            .into_iter()
+            .filter(|at| *at > now)
+            .min();
+        let deadline = async {
+            match wake_at {
+                Some(at) => Timer::at(at).await,
+                None => core::future::pending().await,
+            }
+        };
+
+        let next = select4(
+            REFRESHED.wait(),
+            messages.next_message_pure(),
+            verifying_receiver.changed(),
+            deadline,
+        );
+        match next.await {
+            Either4::First(delay) => next_refresh = Instant::now() + delay,
+            Either4::Second(Message::Connected) => ble_connected = true,
+            Either4::Second(Message::Disconnected) => ble_connected = false,
+            Either4::Second(Message::Battery(battery)) => battery_low = battery.low,
+            Either4::Second(Message::FirmwareUpdate(percent)) => updating = percent.is_some(),
+            Either4::Second(Message::Page(_)) => {
+                awake_until = awake_until.max(Instant::now() + BUTTON_GRACE)
+            }
+            Either4::Third(pending) => verifying = pending,
+            Either4::Second(_) | Either4::Fourth(_) => {}
+        }
+    }
+}
+
+/// Lets the display catch up, then sleeps for `duration`. Doesn't return, waking up reboots.
+async fn sleep(rtc: &mut Rtc<'_>, duration: Duration) -> ! {
+    PUB_SUB_CHANNEL
+        .immediate_publisher()
+        .publish_immediate(Message::Sleep);
+    if with_timeout(DISPLAY_TIMEOUT, DISPLAY_SETTLED.wait())
+        .await
+        .is_err()
+    {
+        warn!("Display didn't settle, sleeping anyway");
+    }
+
+    info!("Sleeping for {}s", duration.as_secs());
+    let timer = TimerWakeupSource::new(core::time::Duration::from_millis(duration.as_millis()));
+    rtc.sleep_deep(&[&timer])
+}
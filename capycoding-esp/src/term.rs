@@ -1,6 +1,9 @@
 use alloc::boxed::Box;
+use core::fmt::Write;
+use core::iter;
+use core::sync::atomic::{AtomicBool, Ordering};
 use display_interface_spi::SPIInterface;
-use embassy_time::Timer;
+use embassy_futures::select::{Either, select};
 use embedded_hal_bus::spi::ExclusiveDevice;
 use log::info;
 use weact_studio_epd::{
@@ -19,10 +22,17 @@ use esp_hal::{
     spi::master::Spi,
 };
 
-use crate::{CapyConfigHandle, ui::root_draw};
+use crate::{
+    CapyConfigHandle, ConfigError, DEFAULT_FULL_REFRESH_EVERY, Message, PUB_SUB_CHANNEL, power,
+    ui::{UiState, handle_message, root_draw, root_screen},
+    wifi::WIFI_STATUS,
+};
 
 pub type CapyDisplay = Display<128, 296, 4736, weact_studio_epd::Color>;
 
+/// Makes the next flush a full refresh instead of a fast one, `ui_task` decides.
+static FULL_REFRESH: AtomicBool = AtomicBool::new(true);
+
 pub type CapyTerm<'a> = Terminal<
     EmbeddedBackend<'a, Display<128, 296, 4736, weact_studio_epd::Color>, weact_studio_epd::Color>,
 >;
@@ -56,9 +66,14 @@ pub fn setup_weact_term<'a>(
     driver.init().unwrap();
 
     let config = EmbeddedBackendConfig {
-        font_regular: fonts::MONO_10X20,
+        font_regular: fonts::MONO_6X13,
+        font_bold: Some(fonts::MONO_6X13_BOLD),
         flush_callback: Box::new(move |d| {
-            driver.fast_update(d).unwrap();
+            if FULL_REFRESH.load(Ordering::Relaxed) {
+                driver.full_update(d).unwrap();
+            } else {
+                driver.fast_update(d).unwrap();
+            }
         }),
         ..Default::default()
     };
@@ -74,18 +89,87 @@ pub async fn ui_task(
     spi: Spi<'static, Blocking>,
     term_init_pins: WeactTermInitPins,
     config_ref: CapyConfigHandle,
+    config_error: Option<ConfigError>,
 ) {
     info!("UI task started!");
     let mut display = Display290BlackWhite::new();
     let mut term = setup_weact_term(spi, &mut display, term_init_pins);
+    let mut messages = PUB_SUB_CHANNEL.subscriber().unwrap();
+    let mut state = UiState {
+        firmware: env!("CARGO_PKG_VERSION"),
+        ..Default::default()
+    };
+    if let Some(e) = config_error {
+        let mut message = heapless::String::new();
+        // every `ConfigError` reads shorter than this
+        let _ = write!(message, "{e}");
+        state.config_error = Some(message);
+    }
+    let mut wifi_status = WIFI_STATUS.receiver().unwrap();
+    // what's on screen, e-paper is slow and flashes, so only redraw when something changed
+    let mut drawn: Option<UiState> = None;
+    // fast updates leave a little ghosting behind each time, a full refresh clears it
+    let mut fast_updates: u8 = 0;
+    // after a deep sleep the panel still shows what we had before, keep it until the
+    // refresh is done instead of flashing up an empty dashboard in between
+    let mut hold = power::woke_from_sleep();
+    let mut woken_by: Option<Message> = None;
+
     loop {
-        let config: embassy_sync::mutex::MutexGuard<
-            '_,
-            embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex,
-            Option<crate::CapyConfig>,
-        > = config_ref.lock().await;
-
-        term.draw(|f| root_draw(f, config)).unwrap();
-        Timer::after_millis(5).await;
+        let mut settle = false;
+        let pending = woken_by.take().into_iter();
+        for message in pending.chain(iter::from_fn(|| messages.try_next_message_pure())) {
+            settle |= matches!(message, Message::Sleep);
+            hold &= !matches!(
+                message,
+                Message::Sleep | Message::Page(_) | Message::Connected | Message::FirmwareUpdate(_)
+            );
+            handle_message(&mut state, message);
+        }
+
+        let full_refresh_every = match config_ref.lock().await.as_ref() {
+            Some(config) => {
+                state.provisioned = true;
+                state.reset = false;
+                state.device_name.clone_from(&config.device_name);
+                state.github_user.clone_from(&config.github_user);
+                state.server_url.clear();
+                // the configured URL is at most as long as this, so is the default
+                let _ = state.server_url.push_str(config.server_url());
+                config.full_refresh_every()
+            }
+            None => {
+                state.provisioned = false;
+                state.device_name.clear();
+                state.github_user.clear();
+                state.server_url.clear();
+                DEFAULT_FULL_REFRESH_EVERY
+            }
+        };
+
+        if !hold && drawn.as_ref() != Some(&state) {
+            // a whole new screen would ghost the most, so it gets a full refresh too
+            let full = match &drawn {
+                Some(drawn) => {
+                    fast_updates >= full_refresh_every || root_screen(drawn) != root_screen(&state)
+                }
+                None => true,
+            };
+            FULL_REFRESH.store(full, Ordering::Relaxed);
+            fast_updates = if full { 0 } else { fast_updates + 1 };
+
+            term.draw(|f| root_draw(f, &state)).unwrap();
+            drawn = Some(state.clone());
+        }
+        if settle {
+            power::DISPLAY_SETTLED.signal(());
+        }
+
+        // messages and Wi-Fi status are all that change the state, provisioning
+        // and factory resets touch the config but also always send one of them
+        match select(messages.next_message_pure(), wifi_status.changed()).await {
+            Either::First(message) => woken_by = Some(message),
+            Either::Second(status) => state.wifi = Some(status),
+        }
     }
 }
@@ -1,30 +1,24 @@
-use ratatui::{
-    Frame,
-    style::{Style, Stylize},
-    widgets::{Block, Paragraph, Wrap},
-};
+//! Glue between the firmware's messages and the screens in `capycoding-ui`.
 
-use crate::CapyConfigHandle;
+pub use capycoding_ui::{UiState, root_draw, root_screen};
 
-/// The root of the widget tree that draws everything else;
-pub fn root_draw(
-    frame: &mut Frame,
+use crate::Message;
 
-    config: embassy_sync::mutex::MutexGuard<
-        '_,
-        embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex,
-        Option<crate::CapyConfig>,
-    >,
-) {
-    let text = if config.is_none() {
-        "Please connect to me!"
-    } else {
-        "CONFIG present!"
-    };
-
-    let paragraph = Paragraph::new(text.dark_gray()).wrap(Wrap { trim: true });
-    let bordered_block = Block::bordered()
-        .border_style(Style::new().yellow())
-        .title("Mousefood");
-    frame.render_widget(paragraph.block(bordered_block), frame.area());
+pub fn handle_message(state: &mut UiState, message: Message) {
+    match message {
+        Message::FactoryReset => state.factory_reset(),
+        Message::PairingPasskey(passkey) => state.passkey = Some(passkey),
+        Message::PairingDone => state.passkey = None,
+        Message::ClaudeMetrics(metrics) => state.set_claude(metrics),
+        Message::PullRequests(pull_requests) => state.pull_requests = pull_requests,
+        Message::WorkflowRuns(workflow_runs) => state.workflow_runs = workflow_runs,
+        Message::Commits(commits) => state.commits = Some(commits),
+        Message::ServerUntrusted(untrusted) => state.server_untrusted = untrusted,
+        Message::Connected => state.ble_connected = true,
+        Message::Disconnected => state.ble_connected = false,
+        Message::Page(change) => state.change_page(change),
+        Message::Battery(battery) => state.battery = Some(battery),
+        Message::FirmwareUpdate(percent) => state.updating = percent,
+        Message::Sleep => {}
+    }
 }
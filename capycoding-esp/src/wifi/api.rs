@@ -1,4 +1,261 @@
-const BASE_ADDRESS: &str = "https://cappycoding.koyeb.app/";
-struct Api {}
+//! Client for the CappyCoding server's metrics and firmware endpoints.
+//!
+//! Responses are parsed straight into the fixed size structs from [`capycoding_ui::metrics`],
+//! which the display draws as they are.
 
-impl Api {}
+use core::fmt::{self, Write};
+
+use embassy_net::{
+    Stack,
+    dns::DnsSocket,
+    tcp::client::{TcpClient, TcpClientState},
+};
+use embedded_tls::TlsError;
+use esp_hal::rng::Rng;
+use heapless::{String, Vec};
+use reqwless::client::{HttpClient, TlsConfig, TlsVerify};
+use reqwless::request::{Method, RequestBuilder};
+use serde::Deserialize;
+use serde::de::DeserializeOwned;
+
+use alloc::vec;
+use ble_types::Tokens;
+
+pub use capycoding_ui::metrics::{
+    ClaudeMetrics, CommitMetrics, MAX_ITEMS, PullRequest, WorkflowRun,
+};
+
+/// Root an `https` server's certificate chain has to lead back to, in DER.
+///
+/// embedded-tls matches the host against the certificate's common name exactly,
+/// so the server needs a certificate for its own name rather than a wildcard one.
+/// Both restrictions are spelled out for whoever runs a server in `server/README.md`.
+const SERVER_CA: &[u8] = include_bytes!("../../certs/isrg-root-x1.der");
+
+const BUF_LEN: usize = 4096;
+/// The longest server URL plus the longest path and query we build.
+const URL_LEN: usize = 192;
+/// Room for the longest string in a response once unescaped, GitHub caps PR titles at 256.
+const UNESCAPE_BUF_LEN: usize = 256;
+/// Firmware images are downloaded in pieces of this many bytes, one range request each.
+pub const PIECE_LEN: usize = 16 * 1024;
+/// A full TLS record, servers sending a large file fill them up.
+const TLS_RECORD_LEN: usize = 16 * 1024 + 256;
+
+#[derive(Debug)]
+pub enum ApiError {
+    /// Connecting, the TLS handshake or the HTTP exchange failed.
+    Http(reqwless::Error),
+    /// The server's certificate didn't check out, someone may be in the middle.
+    Untrusted(TlsError),
+    /// The server answered, but not with a success status.
+    Status(u16),
+    /// The response body isn't what we expected.
+    Json(serde_json_core::de::Error),
+    /// The request URL doesn't fit in its buffer.
+    UrlTooLong,
+    /// The server sent less of the firmware image than it announced.
+    Truncated,
+}
+
+impl From<reqwless::Error> for ApiError {
+    fn from(e: reqwless::Error) -> Self {
+        match e {
+            reqwless::Error::Tls(
+                e @ (TlsError::InvalidCertificate
+                | TlsError::InvalidSignature
+                | TlsError::InvalidSignatureScheme),
+            ) => ApiError::Untrusted(e),
+            e => ApiError::Http(e),
+        }
+    }
+}
+
+impl From<serde_json_core::de::Error> for ApiError {
+    fn from(e: serde_json_core::de::Error) -> Self {
+        ApiError::Json(e)
+    }
+}
+
+/// The firmware release the server offers, see `GET /firmware/latest`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+    pub version: String<16>,
+    /// Size of the image in bytes.
+    pub size: u32,
+    /// SHA-256 of the image, in lowercase hex.
+    pub sha256: String<64>,
+    /// Ed25519 signature of the SHA-256 by the release key, in lowercase hex.
+    pub signature: String<128>,
+}
+
+pub struct Api<'a> {
+    stack: Stack<'a>,
+    rng: Rng,
+    base: &'a str,
+    tokens: &'a Tokens,
+}
+
+impl<'a> Api<'a> {
+    /// `base` is the server URL without a trailing slash. Empty `tokens` are left out of
+    /// requests, without a GitHub token the server falls back to its own.
+    pub fn new(stack: Stack<'a>, rng: Rng, base: &'a str, tokens: &'a Tokens) -> Self {
+        Self {
+            stack,
+            rng,
+            base,
+            tokens,
+        }
+    }
+
+    pub async fn claude(&self) -> Result<ClaudeMetrics, ApiError> {
+        let url = self.url(format_args!("/metrics/claude"))?;
+        self.get(&url).await
+    }
+
+    /// The most recently updated pull requests authored by `user`.
+    pub async fn pull_requests(&self, user: &str) -> Result<Vec<PullRequest, MAX_ITEMS>, ApiError> {
+        let url = self.url(format_args!(
+            "/metrics/prs?user={user}&per_page={MAX_ITEMS}"
+        ))?;
+        self.get(&url).await
+    }
+
+    /// The latest workflow runs across `user`'s repositories.
+    pub async fn workflow_runs(&self, user: &str) -> Result<Vec<WorkflowRun, MAX_ITEMS>, ApiError> {
+        let url = self.url(format_args!(
+            "/metrics/workflows?user={user}&per_page={MAX_ITEMS}"
+        ))?;
+        self.get(&url).await
+    }
+
+    pub async fn commits(&self, user: &str) -> Result<CommitMetrics, ApiError> {
+        let url = self.url(format_args!("/metrics/commits?user={user}"))?;
+        self.get(&url).await
+    }
+
+    /// The firmware release on offer, `None` if the server doesn't serve any.
+    pub async fn firmware_release(&self) -> Result<Option<Release>, ApiError> {
+        let url = self.url(format_args!("/firmware/latest"))?;
+        match self.get(&url).await {
+            Ok(release) => Ok(Some(release)),
+            Err(ApiError::Status(404)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Downloads the first `size` bytes of the firmware image over a single connection,
+    /// handing it to `write` in pieces of at most [`PIECE_LEN`] bytes.
+    pub async fn firmware_image<E: From<ApiError>>(
+        &self,
+        size: u32,
+        mut write: impl AsyncFnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        // too big for the stack
+        let mut rx_buffer = vec![0; TLS_RECORD_LEN];
+        let mut tx_buffer = [0; BUF_LEN];
+        let dns = DnsSocket::new(self.stack);
+        let tcp_state = TcpClientState::<1, BUF_LEN, BUF_LEN>::new();
+        let tcp = TcpClient::new(self.stack, &tcp_state);
+        let tls = self.tls(&mut rx_buffer, &mut tx_buffer);
+        let mut client = HttpClient::new_with_tls(&tcp, &dns, tls);
+        let mut resource = client.resource(self.base).await.map_err(ApiError::from)?;
+
+        let mut bearer = String::new();
+        let auth_headers = self.headers(&mut bearer);
+        // the piece plus room for the response headers
+        let mut buffer = vec![0u8; PIECE_LEN + 1024];
+
+        let mut offset = 0;
+        while offset < size {
+            let end = (offset + PIECE_LEN as u32).min(size) - 1;
+            let mut range = String::<32>::new();
+            let _ = write!(range, "bytes={offset}-{end}");
+            let mut headers: Vec<_, 3> = auth_headers.iter().copied().collect();
+            let _ = headers.push(("Range", range.as_str()));
+
+            let response = resource
+                .get("/firmware/image")
+                .headers(&headers)
+                .send(&mut buffer)
+                .await
+                .map_err(ApiError::from)?;
+            if !response.status.is_successful() {
+                return Err(ApiError::Status(response.status.0).into());
+            }
+
+            let piece = response
+                .body()
+                .read_to_end()
+                .await
+                .map_err(ApiError::from)?;
+            if piece.is_empty() {
+                return Err(ApiError::Truncated.into());
+            }
+            // a server ignoring the range sends everything, only take what was asked for
+            let piece = &piece[..piece.len().min((end + 1 - offset) as usize)];
+
+            write(piece).await?;
+            offset += piece.len() as u32;
+        }
+        Ok(())
+    }
+
+    fn url(&self, path: fmt::Arguments<'_>) -> Result<String<URL_LEN>, ApiError> {
+        let mut url = String::new();
+        write!(url, "{}{}", self.base, path).map_err(|_| ApiError::UrlTooLong)?;
+        Ok(url)
+    }
+
+    /// GETs `url` over a fresh connection and parses the JSON body.
+    async fn get<T: DeserializeOwned>(&self, url: &str) -> Result<T, ApiError> {
+        let mut rx_buffer = [0; BUF_LEN];
+        let mut tx_buffer = [0; BUF_LEN];
+        let dns = DnsSocket::new(self.stack);
+        let tcp_state = TcpClientState::<1, BUF_LEN, BUF_LEN>::new();
+        let tcp = TcpClient::new(self.stack, &tcp_state);
+        let tls = self.tls(&mut rx_buffer, &mut tx_buffer);
+        let mut client = HttpClient::new_with_tls(&tcp, &dns, tls);
+
+        let mut bearer = String::new();
+        let headers = self.headers(&mut bearer);
+
+        let mut buffer = [0u8; BUF_LEN];
+        let mut request = client.request(Method::GET, url).await?.headers(&headers);
+        let response = request.send(&mut buffer).await?;
+
+        if !response.status.is_successful() {
+            return Err(ApiError::Status(response.status.0));
+        }
+
+        let body = response.body().read_to_end().await?;
+        let mut unescape_buffer = [0u8; UNESCAPE_BUF_LEN];
+        let (value, _) = serde_json_core::from_slice_escaped(body, &mut unescape_buffer)?;
+        Ok(value)
+    }
+
+    fn tls<'b>(&self, rx_buffer: &'b mut [u8], tx_buffer: &'b mut [u8]) -> TlsConfig<'b> {
+        // every connection gets its own seed, reusing one would reuse the TLS keys
+        let seed = self.rng.random() as u64 | ((self.rng.random() as u64) << 32);
+        let verify = TlsVerify::Certificate {
+            ca: SERVER_CA,
+            cert: None,
+            key: None,
+        };
+        TlsConfig::new(seed, rx_buffer, tx_buffer, verify)
+    }
+
+    /// The headers carrying our `tokens`, `bearer` holds the `Authorization` value.
+    fn headers<'b>(&'b self, bearer: &'b mut String<{ 7 + 64 }>) -> Vec<(&'b str, &'b str), 2> {
+        let mut headers = Vec::new();
+        if !self.tokens.github.is_empty() {
+            let _ = headers.push(("X-GitHub-Token", self.tokens.github.as_str()));
+        }
+        if !self.tokens.server.is_empty() {
+            // the buffer fits "Bearer " plus the longest token
+            let _ = write!(bearer, "Bearer {}", self.tokens.server);
+            let _ = headers.push(("Authorization", bearer.as_str()));
+        }
+        headers
+    }
+}
@@ -0,0 +1,178 @@
+//! Periodically pulls metrics from the server and publishes the ones that changed,
+//! checking for firmware updates every now and then.
+
+use embassy_net::Stack;
+use embassy_time::{Duration, Instant, Timer};
+use esp_hal::rng::Rng;
+use heapless::{String, Vec};
+use log::{info, warn};
+
+use ble_types::{ProvisioningStatus, Tokens};
+
+use super::WIFI_STATUS;
+use super::api::{
+    Api, ApiError, ClaudeMetrics, CommitMetrics, MAX_ITEMS, PullRequest, WorkflowRun,
+};
+use crate::{
+    CapyConfig, CapyConfigHandle, CapyFlashHandle, DEFAULT_REFRESH_SECS, DEFAULT_SERVER_URL,
+    Message, PUB_SUB_CHANNEL, ota, power,
+};
+
+/// First retry delay after a failed refresh, doubled on every failure after that.
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+/// Time between firmware update checks. Devices waking from deep sleep check on every wake.
+const UPDATE_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// What was last published, so unchanged metrics don't make the UI redraw.
+#[derive(Default)]
+struct Published {
+    claude: Option<Option<ClaudeMetrics>>,
+    pull_requests: Option<Vec<PullRequest, MAX_ITEMS>>,
+    workflow_runs: Option<Vec<WorkflowRun, MAX_ITEMS>>,
+    commits: Option<CommitMetrics>,
+    untrusted: Option<bool>,
+}
+
+/// The parts of the config a refresh needs, copied out so the config isn't locked meanwhile.
+struct Settings {
+    server_url: String<96>,
+    tokens: Tokens,
+    github_user: String<39>,
+    interval: Duration,
+}
+
+impl Settings {
+    fn from_config(config: Option<&CapyConfig>) -> Self {
+        match config {
+            Some(config) => Self {
+                // `server_url` is at most as long as the stored one or the default
+                server_url: String::try_from(config.server_url()).unwrap_or_default(),
+                tokens: config.api_tokens.clone(),
+                github_user: config.github_user.clone(),
+                interval: config.refresh_interval(),
+            },
+            None => Self {
+                server_url: String::try_from(DEFAULT_SERVER_URL).unwrap_or_default(),
+                tokens: Tokens::default(),
+                github_user: String::new(),
+                interval: Duration::from_secs(DEFAULT_REFRESH_SECS.into()),
+            },
+        }
+    }
+}
+
+/// Refreshes the metrics on the configured interval, backing off while the server fails us.
+pub(super) async fn refresh(
+    stack: Stack<'static>,
+    rng: Rng,
+    config_handle: CapyConfigHandle,
+    flash_handle: CapyFlashHandle,
+) {
+    let mut published = Published::default();
+    let mut backoff = MIN_BACKOFF;
+    let mut last_update_check: Option<Instant> = None;
+
+    loop {
+        // read the settings every time, so reprovisioning takes effect without a reconnect
+        let settings = Settings::from_config(config_handle.lock().await.as_ref());
+
+        let api = Api::new(stack, rng, &settings.server_url, &settings.tokens);
+        let delay = match fetch(&api, &settings.github_user, &mut published).await {
+            Ok(()) => {
+                WIFI_STATUS
+                    .sender()
+                    .send(ProvisioningStatus::ServerReachable);
+                publish_if_changed(&mut published.untrusted, false, Message::ServerUntrusted);
+                ota::HEALTHY.signal(());
+                backoff = MIN_BACKOFF;
+
+                if last_update_check.is_none_or(|at| at.elapsed() >= UPDATE_INTERVAL) {
+                    last_update_check = Some(Instant::now());
+                    if let Err(e) = ota::update(&api, flash_handle).await {
+                        warn!("Firmware update failed: {e:?}");
+                    }
+                }
+                settings.interval
+            }
+            Err(e) => {
+                if let ApiError::Untrusted(_) = e {
+                    // most likely a captive portal or someone listening in, keep
+                    // trying in case we're on a different network next time
+                    WIFI_STATUS
+                        .sender()
+                        .send(ProvisioningStatus::ServerUntrusted);
+                    publish_if_changed(&mut published.untrusted, true, Message::ServerUntrusted);
+                }
+
+                warn!(
+                    "Metrics refresh failed, retrying in {}s: {:?}",
+                    backoff.as_secs(),
+                    e
+                );
+                let delay = backoff;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                delay
+            }
+        };
+
+        power::REFRESHED.signal(delay);
+        Timer::after(delay).await;
+    }
+}
+
+/// Fetches every metric, publishing the ones that differ from what was `published` before.
+///
+/// The GitHub metrics are skipped until a GitHub user has been provisioned.
+async fn fetch(
+    api: &Api<'_>,
+    github_user: &str,
+    published: &mut Published,
+) -> Result<(), ApiError> {
+    let claude = match api.claude().await {
+        Ok(metrics) => Some(metrics),
+        // the server is fine, nobody pushed any Claude metrics to it yet
+        Err(ApiError::Status(404)) => None,
+        Err(e) => return Err(e),
+    };
+    publish_if_changed(&mut published.claude, claude, Message::ClaudeMetrics);
+
+    if github_user.is_empty() {
+        return Ok(());
+    }
+
+    let pull_requests = api.pull_requests(github_user).await?;
+    publish_if_changed(
+        &mut published.pull_requests,
+        pull_requests,
+        Message::PullRequests,
+    );
+
+    let workflow_runs = api.workflow_runs(github_user).await?;
+    publish_if_changed(
+        &mut published.workflow_runs,
+        workflow_runs,
+        Message::WorkflowRuns,
+    );
+
+    let commits = api.commits(github_user).await?;
+    publish_if_changed(&mut published.commits, commits, Message::Commits);
+
+    info!("Metrics refreshed");
+    Ok(())
+}
+
+fn publish_if_changed<T: Clone + PartialEq>(
+    last: &mut Option<T>,
+    new: T,
+    message: fn(T) -> Message,
+) {
+    if last.as_ref() == Some(&new) {
+        return;
+    }
+
+    *last = Some(new.clone());
+    PUB_SUB_CHANNEL
+        .immediate_publisher()
+        .publish_immediate(message(new));
+}
@@ -1,30 +1,64 @@
-use esp_radio::wifi::ScanConfig;
-use reqwless::client::{HttpClient, TlsConfig};
+use ble_types::{AuthMethod, ProvisioningStatus, ScannedNetwork, WifiCredentials};
+use embassy_futures::select::{Either, select};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::watch::Watch;
+use esp_radio::wifi::{AccessPointInfo, ScanConfig};
 
 
-use embassy_net::{dns::DnsSocket, tcp::client::{TcpClient, TcpClientState}, Config, Runner, Stack, StackResources};
-use embassy_time::{Duration, Timer};
+use embassy_net::{Config, Runner, Stack, StackResources};
+use embassy_time::{Duration, Instant, Timer};
 use esp_hal::peripherals;
+use esp_hal::rng::Rng;
 use esp_radio::{wifi::{ModeConfig, WifiController, WifiDevice, WifiEvent, WifiStaState}, Controller};
-use log::info;
+use log::{info, warn};
 
-use crate::CapyConfigHandle;
+use crate::{CapyConfigHandle, CapyFlashHandle, MAX_NETWORKS};
 use alloc::string::String;
+use alloc::vec::Vec;
+use core::cmp::Reverse;
 
-const SSID: &str = "Surendra S21";
-const PASSWORD: &str = "Surendra2006";
+pub mod api;
+mod metrics;
 
-mod api;
+/// Signalled whenever new Wi-Fi credentials are written, so the `connection` task can
+/// reconfigure the controller without a reboot.
+pub static WIFI_CREDENTIALS_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
-#[embassy_executor::task]
-pub async fn wifi_task(stack: Stack<'static>, tls_seed: u64) {
-    wait_for_connection(stack).await;
+/// How far joining the provisioned network got, reported to the app over BLE.
+pub static WIFI_STATUS: Watch<CriticalSectionRawMutex, ProvisioningStatus, 2> =
+    Watch::new_with(ProvisioningStatus::Unprovisioned);
 
+/// Most networks reported back from a single scan.
+pub const MAX_SCAN_RESULTS: usize = 16;
 
-    access_website(stack, tls_seed).await;
-   
-}
+/// Networks found by a scan, strongest first.
+pub type ScanResults = heapless::Vec<ScannedNetwork, MAX_SCAN_RESULTS>;
+
+/// Signalled to ask the `connection` task for a scan, answered through [`SCAN_RESULTS`].
+pub static SCAN_REQUESTED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+pub static SCAN_RESULTS: Signal<CriticalSectionRawMutex, ScanResults> = Signal::new();
+
+/// Keeps the dashboard metrics and the firmware fresh for as long as we're on a network.
+#[embassy_executor::task]
+pub async fn wifi_task(
+    stack: Stack<'static>,
+    rng: Rng,
+    config_handle: CapyConfigHandle,
+    flash_handle: CapyFlashHandle,
+) {
+    loop {
+        wait_for_connection(stack).await;
+        WIFI_STATUS.sender().send(ProvisioningStatus::GotIp);
 
+        select(
+            stack.wait_config_down(),
+            metrics::refresh(stack, rng, config_handle, flash_handle),
+        )
+        .await;
+        info!("Lost the network, metrics refresh paused");
+    }
+}
 
 #[embassy_executor::task]
 pub async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
@@ -32,72 +66,235 @@ pub async fn net_task(mut runner: Runner<'static, WifiDevice<'static>>) {
 }
 
 #[embassy_executor::task]
-pub async fn connection(mut controller: WifiController<'static>) {
+pub async fn connection(mut controller: WifiController<'static>, config_handle: CapyConfigHandle) {
     info!("start connection task");
     info!("Device capabilities: {:?}", controller.capabilities());
-    
+
+    // start straight away, an unprovisioned device has to be able to scan for the app
+    controller
+        .set_config(&ModeConfig::Client(Default::default()))
+        .unwrap();
+    info!("Starting WiFi controller...");
+    controller.start_async().await.unwrap();
+
+    loop {
+        let networks = wait_for_networks(config_handle, &mut controller).await;
+        info!("Know {} wifi networks", networks.len());
+        WIFI_STATUS.sender().send(ProvisioningStatus::Connecting);
+
+        // stay on these networks until someone provisions a new one
+        if let Either::First(_) = select(
+            WIFI_CREDENTIALS_CHANGED.wait(),
+            stay_connected(&mut controller, &networks),
+        )
+        .await
+        {
+            info!("Wifi credentials changed, reconnecting");
+            if esp_radio::wifi::sta_state() == WifiStaState::Connected {
+                let _ = controller.disconnect_async().await;
+            }
+        }
+    }
+}
+
+/// Blocks until the config knows at least one network, answering scans meanwhile.
+async fn wait_for_networks(
+    config_handle: CapyConfigHandle,
+    controller: &mut WifiController<'static>,
+) -> heapless::Vec<WifiCredentials, MAX_NETWORKS> {
+    loop {
+        if let Some(config) = config_handle.lock().await.as_ref()
+            && !config.networks.is_empty()
+        {
+            return config.networks.clone();
+        }
+
+        info!("No wifi credentials yet, waiting for provisioning");
+        WIFI_STATUS.sender().send(ProvisioningStatus::Unprovisioned);
+        while let Either::Second(_) =
+            select(WIFI_CREDENTIALS_CHANGED.wait(), SCAN_REQUESTED.wait()).await
+        {
+            answer_scan(controller).await;
+        }
+    }
+}
+
+/// Keeps the controller connected to the best of the known `networks`.
+///
+/// Every attempt starts with a scan, then works through the known networks in range,
+/// strongest first, before trying the ones that weren't seen (they might be hidden).
+/// The scan also tells a missing access point apart from a rejected password.
+async fn stay_connected(controller: &mut WifiController<'static>, networks: &[WifiCredentials]) {
     loop {
         if esp_radio::wifi::sta_state() == WifiStaState::Connected {
             // wait until we're no longer connected
-            controller.wait_for_event(WifiEvent::StaDisconnected).await;
-            Timer::after(Duration::from_millis(5000)).await
+            while esp_radio::wifi::sta_state() == WifiStaState::Connected {
+                let woken = select(
+                    controller.wait_for_event(WifiEvent::StaDisconnected),
+                    SCAN_REQUESTED.wait(),
+                )
+                .await;
+                if let Either::Second(_) = woken {
+                    answer_scan(controller).await;
+                }
+            }
+            WIFI_STATUS.sender().send(ProvisioningStatus::Connecting);
+            sleep_answering_scans(controller, Duration::from_millis(5000)).await
         }
 
-        if !matches!(controller.is_started(), Ok(true)) {
-            info!("Starting WiFi controller...");
-            
-            let ssid: String = SSID.into();
-            let password: String = PASSWORD.into();
+        let found = scan(controller).await;
+        let (candidates, in_range) = rank_networks(networks, &found);
 
-            let wifi_config = esp_radio::wifi::ClientConfig::default()
-                .with_failure_retry_cnt(5)
-                .with_ssid(ssid)
-                .with_password(password);
-
-            let mode_client = ModeConfig::Client(wifi_config);
-            controller.set_config(&mode_client).unwrap();
-            controller.start_async().await.unwrap();
+        if connect_to_any(controller, &candidates).await {
+            info!("Wifi connected!");
+            continue;
         }
-        
-        info!("About to connect...");
+
+        let status = if in_range {
+            ProvisioningStatus::AuthFailed
+        } else {
+            ProvisioningStatus::ApNotFound
+        };
+        WIFI_STATUS.sender().send(status);
+
+        // Wait before retrying connection
+        sleep_answering_scans(controller, Duration::from_millis(5000)).await
+    }
+}
+
+/// Orders `networks` strongest first by what a scan `found`, with unseen networks last
+/// in priority order. Also returns whether any of them were seen at all.
+fn rank_networks<'a>(
+    networks: &'a [WifiCredentials],
+    found: &[AccessPointInfo],
+) -> (heapless::Vec<&'a WifiCredentials, MAX_NETWORKS>, bool) {
+    let signal = |network: &WifiCredentials| {
+        found
+            .iter()
+            .filter(|ap| ap.ssid.as_str() == network.ssid.as_str())
+            .map(|ap| ap.signal_strength)
+            .max()
+    };
+
+    let mut ranked: heapless::Vec<_, MAX_NETWORKS> = networks.iter().collect();
+    // the sort is stable, so equally strong (or unseen) networks keep their priority order
+    ranked.sort_by_key(|network| Reverse(signal(network)));
+
+    let in_range = ranked
+        .first()
+        .is_some_and(|network| signal(network).is_some());
+    (ranked, in_range)
+}
+
+/// Tries `candidates` in order until one of them lets us in.
+async fn connect_to_any(
+    controller: &mut WifiController<'static>,
+    candidates: &[&WifiCredentials],
+) -> bool {
+    for network in candidates {
+        info!("About to connect to {:?}...", network.ssid);
+
+        let wifi_config = esp_radio::wifi::ClientConfig::default()
+            .with_failure_retry_cnt(5)
+            .with_ssid(String::from(network.ssid.as_str()))
+            .with_password(String::from(network.password.as_str()));
+
+        controller
+            .set_config(&ModeConfig::Client(wifi_config))
+            .unwrap();
 
         match controller.connect_async().await {
-            Ok(_) => {
-                info!("Wifi connected!");
-                // Connection successful, continue the loop
-            }
-            Err(e) => {
-                info!("Failed to connect to wifi: {e:?}");
-                info!("Performing network scan to find available networks...");
-                
-                // Perform scan on failure
-                let scan_config = ScanConfig::default();
-                
-                match controller.scan_with_config_async(scan_config).await {
-                    Ok(scan_results) => {
-                        info!("=== WiFi Networks Found ===");
-                        for (idx, ap) in scan_results.iter().enumerate() {
-                            info!(
-                                "{}. SSID: {:?}, Channel: {}, RSSI: {}, Auth: {:?}",
-                                idx + 1,
-                                ap.ssid,
-                                ap.channel,
-                                ap.signal_strength,
-                                ap.auth_method
-                            );
-                        }
-                        info!("=== End of scan results ===");
-                    }
-                    Err(scan_err) => {
-                        info!("Scan also failed: {:?}", scan_err);
-                    }
-                }
-                
-                // Wait before retrying connection
-                Timer::after(Duration::from_millis(5000)).await
+            Ok(_) => return true,
+            Err(e) => info!("Failed to connect to {:?}: {e:?}", network.ssid),
+        }
+    }
+    false
+}
+
+/// Scans for networks, logging what was found. A failed scan finds nothing.
+async fn scan(controller: &mut WifiController<'static>) -> Vec<AccessPointInfo> {
+    match controller
+        .scan_with_config_async(ScanConfig::default())
+        .await
+    {
+        Ok(found) => {
+            info!("=== WiFi Networks Found ===");
+            for (idx, ap) in found.iter().enumerate() {
+                info!(
+                    "{}. SSID: {:?}, Channel: {}, RSSI: {}, Auth: {:?}",
+                    idx + 1,
+                    ap.ssid,
+                    ap.channel,
+                    ap.signal_strength,
+                    ap.auth_method
+                );
             }
+            info!("=== End of scan results ===");
+            found
+        }
+        Err(e) => {
+            warn!("Scan failed: {:?}", e);
+            Vec::new()
+        }
+    }
+}
+
+/// Sleeps for `duration`, answering scan requests meanwhile.
+async fn sleep_answering_scans(controller: &mut WifiController<'static>, duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while let Either::Second(_) = select(Timer::at(deadline), SCAN_REQUESTED.wait()).await {
+        answer_scan(controller).await;
+    }
+}
+
+/// Scans for networks and hands them to whoever asked through [`SCAN_RESULTS`].
+///
+/// Hidden networks are left out, and an access point seen on several channels
+/// is only listed once.
+async fn answer_scan(controller: &mut WifiController<'static>) {
+    let mut found = scan(controller).await;
+    found.sort_unstable_by_key(|ap| Reverse(ap.signal_strength));
+
+    let mut results = ScanResults::new();
+    for network in found.iter().filter_map(scanned_network) {
+        if results.iter().any(|seen| seen.ssid == network.ssid) {
+            continue;
+        }
+        if results.push(network).is_err() {
+            break;
         }
     }
+    info!("Scan found {} networks", results.len());
+
+    SCAN_RESULTS.signal(results);
+}
+
+fn scanned_network(ap: &AccessPointInfo) -> Option<ScannedNetwork> {
+    use esp_radio::wifi::AuthMethod as Radio;
+
+    let auth = match ap.auth_method {
+        Some(Radio::None) => AuthMethod::Open,
+        Some(Radio::Wep) => AuthMethod::Wep,
+        Some(Radio::Wpa) => AuthMethod::Wpa,
+        Some(Radio::Wpa2Personal) => AuthMethod::Wpa2Personal,
+        Some(Radio::WpaWpa2Personal) => AuthMethod::WpaWpa2Personal,
+        Some(Radio::Wpa2Enterprise) => AuthMethod::Wpa2Enterprise,
+        Some(Radio::Wpa3Personal) => AuthMethod::Wpa3Personal,
+        Some(Radio::Wpa2Wpa3Personal) => AuthMethod::Wpa2Wpa3Personal,
+        Some(Radio::WapiPersonal) => AuthMethod::WapiPersonal,
+        _ => AuthMethod::Unknown,
+    };
+
+    if ap.ssid.is_empty() {
+        return None;
+    }
+
+    Some(ScannedNetwork {
+        ssid: heapless::String::try_from(ap.ssid.as_str()).ok()?,
+        rssi: ap.signal_strength,
+        channel: ap.channel,
+        auth,
+    })
 }
 
 async fn wait_for_connection(stack: Stack<'_>) {
@@ -118,45 +315,3 @@ async fn wait_for_connection(stack: Stack<'_>) {
         Timer::after(Duration::from_millis(500)).await;
     }
 }
-
-async fn access_website(
-    // stack: &'static Stack<WifiDevice<'static, WifiStaDevice>>
-    stack: Stack<'_>
-
-//     stack: &Stack<esp_radio::wifi::WifiDevice>,
-    
-
-
-    , tls_seed: u64) {
-
-    let mut rx_buffer = [0; 4096];
-    let mut tx_buffer = [0; 4096];
-    let dns = DnsSocket::new(stack);
-    let tcp_state = TcpClientState::<1, 4096, 4096>::new();
-    let tcp = TcpClient::new(stack, &tcp_state);
-
-    let tls = TlsConfig::new(
-        tls_seed,
-        &mut rx_buffer,
-        &mut tx_buffer,
-        reqwless::client::TlsVerify::None,
-    );
-
-    let mut client = HttpClient::new_with_tls(&tcp, &dns, tls);
-    let mut buffer = [0u8; 4096];
-    let mut http_req = client
-        .request(
-            reqwless::request::Method::GET,
-            // "https://jsonplaceholder.typicode.com/posts/1",
-"https://cappycoding.koyeb.app/"
-        )
-        .await
-        .unwrap();
-    let response = http_req.send(&mut buffer).await.unwrap();
-
-    info!("Got response");
-    let res = response.body().read_to_end().await.unwrap();
-
-    let content = core::str::from_utf8(res).unwrap();
-    info!("{}", content);
-}
@@ -0,0 +1,336 @@
+//! The metrics dashboard shown once the device is provisioned.
+//!
+//! Laid out for the 296x128 panel with a 6x13 font, which leaves 49 columns and 9 rows.
+//! Everything is black on white, emphasis comes from bold and reversed text.
+//!
+//! Every [`Page`] shares the header row, the rest of the screen is up to the page.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use ratatui::{
+    Frame,
+    layout::{Constraint, Layout, Rect},
+    style::{Style, Stylize},
+    symbols,
+    text::{Line, Span},
+    widgets::{Block, Borders, Cell, LineGauge, Paragraph, Row, Sparkline, Table},
+};
+
+use ble_types::ProvisioningStatus;
+
+use crate::{Page, UiState};
+
+pub fn draw(frame: &mut Frame, state: &UiState, title: &str) {
+    let [header, body] =
+        Layout::vertical([Constraint::Length(1), Constraint::Fill(1)]).areas(frame.area());
+
+    draw_header(frame, header, state, title);
+    match state.page {
+        Page::Overview => draw_overview(frame, body, state),
+        Page::Claude => draw_claude_page(frame, body, state),
+        Page::PullRequests => draw_pull_requests_page(frame, body, state),
+        Page::Ci => draw_ci_page(frame, body, state),
+        Page::Device => draw_device_page(frame, body, state),
+    }
+}
+
+/// A bit of everything, Claude on the left and GitHub on the right.
+fn draw_overview(frame: &mut Frame, area: Rect, state: &UiState) {
+    let [claude, github] =
+        Layout::horizontal([Constraint::Percentage(50), Constraint::Percentage(50)]).areas(area);
+
+    draw_claude(frame, claude, state);
+    draw_github(frame, github, state);
+}
+
+/// Device name on the left, page number and connection badges on the right.
+fn draw_header(frame: &mut Frame, area: Rect, state: &UiState, title: &str) {
+    let wifi = match state.wifi {
+        Some(ProvisioningStatus::ServerReachable) => badge("WiFi", true),
+        Some(ProvisioningStatus::GotIp) => badge("WiFi?", true),
+        Some(ProvisioningStatus::ServerUntrusted) => badge("WiFi!", true),
+        _ => badge("WiFi", false),
+    };
+    let mut badges = Line::from(vec![
+        Span::raw(format!("{}/{} ", state.page as usize + 1, Page::COUNT)),
+        badge("BLE", state.ble_connected),
+        Span::raw(" "),
+        wifi,
+    ]);
+    if let Some(battery) = state.battery {
+        badges.push_span(Span::raw(" "));
+        badges.push_span(battery_icon(battery.percent));
+    }
+
+    let [name, status] = Layout::horizontal([
+        Constraint::Fill(1),
+        Constraint::Length(badges.width() as u16),
+    ])
+    .areas(area);
+
+    frame.render_widget(Paragraph::new(title.bold()), name);
+    frame.render_widget(badges, status);
+}
+
+/// A battery with up to three bars of charge, like `[##-]`.
+fn battery_icon(percent: u8) -> Span<'static> {
+    let bars = match percent {
+        0..=10 => "[---]",
+        11..=40 => "[#--]",
+        41..=70 => "[##-]",
+        _ => "[###]",
+    };
+    Span::raw(bars)
+}
+
+/// Lit badges are drawn reversed, unlit ones plain.
+fn badge(label: &str, lit: bool) -> Span<'_> {
+    if lit {
+        Span::styled(label, Style::new().reversed())
+    } else {
+        Span::raw(label).dim()
+    }
+}
+
+/// Cost and tokens, with the burn rate history as a sparkline underneath.
+fn draw_claude(frame: &mut Frame, area: Rect, state: &UiState) {
+    let block = Block::new()
+        .borders(Borders::TOP | Borders::RIGHT)
+        .title(" Claude ");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [numbers, history] =
+        Layout::vertical([Constraint::Length(3), Constraint::Fill(1)]).areas(inner);
+
+    let lines = match &state.claude {
+        Some(claude) => vec![
+            Line::from(format!("${:.2}", claude.total_cost_usd).bold()),
+            Line::from(format!("${:.2}/h", claude.burn_rate_per_hour)),
+            Line::from(format!(
+                "{} tok {} sess",
+                compact(claude.total_tokens),
+                claude.session_count
+            )),
+        ],
+        None => vec![Line::from("No usage yet")],
+    };
+    frame.render_widget(Paragraph::new(lines), numbers);
+
+    let burn_rates: Vec<u64> = state.burn_rate_history.oldest_ordered().copied().collect();
+    frame.render_widget(Sparkline::default().data(&burn_rates), history);
+}
+
+/// Pull requests, commits, the commit streak and how CI is doing.
+fn draw_github(frame: &mut Frame, area: Rect, state: &UiState) {
+    let block = Block::new().borders(Borders::TOP).title(" GitHub ");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [counts, ci, last_run] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Length(1),
+        Constraint::Fill(1),
+    ])
+    .areas(inner);
+
+    let open = state
+        .pull_requests
+        .iter()
+        .filter(|pr| pr.state == "open")
+        .count();
+    let (commits, streak) = match &state.commits {
+        Some(commits) => (format!("{}", commits.total), format!("{}d", commits.streak)),
+        None => ("--".into(), "--".into()),
+    };
+    let counts_lines = vec![
+        Line::from(vec![
+            Span::raw("PRs open "),
+            Span::raw(format!("{open}")).bold(),
+        ]),
+        Line::from(vec![Span::raw("Commits  "), Span::raw(commits).bold()]),
+        Line::from(vec![Span::raw("Streak   "), Span::raw(streak).bold()]),
+    ];
+    frame.render_widget(Paragraph::new(counts_lines), counts);
+
+    let runs = state.workflow_runs.len();
+    let passing = state
+        .workflow_runs
+        .iter()
+        .filter(|run| run.conclusion == "success")
+        .count();
+    let ratio = if runs == 0 {
+        0.0
+    } else {
+        passing as f64 / runs as f64
+    };
+    let gauge = LineGauge::default()
+        .label(format!("CI {passing}/{runs}"))
+        .filled_symbol(symbols::line::THICK_HORIZONTAL)
+        .ratio(ratio);
+    frame.render_widget(gauge, ci);
+
+    let last = match state.workflow_runs.first() {
+        Some(run) => Line::from(vec![
+            Span::raw(run_symbol(&run.status, &run.conclusion)).bold(),
+            Span::raw(" "),
+            Span::raw(run.name.as_str()),
+        ]),
+        None => Line::from("No workflow runs"),
+    };
+    frame.render_widget(Paragraph::new(last), last_run);
+}
+
+/// Everything about Claude usage, with the burn rate history across the whole width.
+fn draw_claude_page(frame: &mut Frame, area: Rect, state: &UiState) {
+    let block = Block::new().borders(Borders::TOP).title(" Claude usage ");
+    let inner = block.inner(area);
+    frame.render_widget(block, area);
+
+    let [numbers, history] =
+        Layout::vertical([Constraint::Length(2), Constraint::Fill(1)]).areas(inner);
+
+    let lines = match &state.claude {
+        Some(claude) => vec![
+            Line::from(vec![
+                Span::raw(format!("${:.2}", claude.total_cost_usd)).bold(),
+                Span::raw(format!(" at ${:.2}/h", claude.burn_rate_per_hour)),
+            ]),
+            Line::from(format!(
+                "{} tokens in {} sessions",
+                compact(claude.total_tokens),
+                claude.session_count
+            )),
+        ],
+        None => vec![Line::from("No usage yet")],
+    };
+    frame.render_widget(Paragraph::new(lines), numbers);
+
+    let burn_rates: Vec<u64> = state.burn_rate_history.oldest_ordered().copied().collect();
+    frame.render_widget(Sparkline::default().data(&burn_rates), history);
+}
+
+/// The latest pull requests, one per row.
+fn draw_pull_requests_page(frame: &mut Frame, area: Rect, state: &UiState) {
+    let block = Block::new().borders(Borders::TOP).title(" Pull requests ");
+    if state.pull_requests.is_empty() {
+        frame.render_widget(Paragraph::new("No pull requests").block(block), area);
+        return;
+    }
+
+    let rows = state.pull_requests.iter().map(|pr| {
+        let status = if pr.merged {
+            "merged"
+        } else {
+            pr.state.as_str()
+        };
+        Row::new([
+            Cell::from(format!("#{}", pr.number)),
+            Cell::from(pr.title.as_str()),
+            Cell::from(status),
+        ])
+    });
+    let widths = [
+        Constraint::Length(6),
+        Constraint::Fill(1),
+        Constraint::Length(6),
+    ];
+    frame.render_widget(Table::new(rows, widths).block(block), area);
+}
+
+/// The latest workflow runs, one per row.
+fn draw_ci_page(frame: &mut Frame, area: Rect, state: &UiState) {
+    let block = Block::new().borders(Borders::TOP).title(" CI ");
+    if state.workflow_runs.is_empty() {
+        frame.render_widget(Paragraph::new("No workflow runs").block(block), area);
+        return;
+    }
+
+    let rows = state.workflow_runs.iter().map(|run| {
+        let outcome = if run.conclusion.is_empty() {
+            run.status.as_str()
+        } else {
+            run.conclusion.as_str()
+        };
+        Row::new([
+            Cell::from(run_symbol(&run.status, &run.conclusion)),
+            Cell::from(run.name.as_str()),
+            Cell::from(outcome),
+        ])
+    });
+    let widths = [
+        Constraint::Length(1),
+        Constraint::Fill(1),
+        Constraint::Length(11),
+    ];
+    frame.render_widget(Table::new(rows, widths).block(block), area);
+}
+
+/// What the device is and where it's getting its metrics from.
+fn draw_device_page(frame: &mut Frame, area: Rect, state: &UiState) {
+    let block = Block::new().borders(Borders::TOP).title(" Device ");
+
+    let wifi = match state.wifi {
+        Some(ProvisioningStatus::Unprovisioned) | None => "not set up",
+        Some(ProvisioningStatus::Connecting) => "connecting",
+        Some(ProvisioningStatus::GotIp) => "online",
+        Some(ProvisioningStatus::AuthFailed) => "wrong password",
+        Some(ProvisioningStatus::ApNotFound) => "network not found",
+        Some(ProvisioningStatus::ServerReachable) => "server reachable",
+        Some(ProvisioningStatus::ServerUntrusted) => "server untrusted",
+    };
+    let github_user = if state.github_user.is_empty() {
+        "--"
+    } else {
+        state.github_user.as_str()
+    };
+
+    let mut lines = vec![
+        Line::from(format!("Firmware {}", state.firmware)),
+        Line::from(format!("Wi-Fi    {wifi}")),
+        Line::from(format!(
+            "BLE      {}",
+            if state.ble_connected {
+                "connected"
+            } else {
+                "advertising"
+            }
+        )),
+        Line::from(format!("GitHub   {github_user}")),
+        Line::from(format!("Server   {}", server_host(&state.server_url))),
+    ];
+    if let Some(battery) = state.battery {
+        lines.push(Line::from(format!("Battery  {}%", battery.percent)));
+    }
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+/// `https://capy.example.com:8443/api` shortened to `capy.example.com:8443`.
+fn server_host(url: &str) -> &str {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    rest.split('/').next().unwrap_or(rest)
+}
+
+/// One character summing up a workflow run.
+fn run_symbol(status: &str, conclusion: &str) -> &'static str {
+    match (status, conclusion) {
+        (_, "success") => "+",
+        (_, "failure" | "timed_out" | "startup_failure") => "x",
+        (_, "cancelled" | "skipped") => "-",
+        ("completed", _) => "?",
+        _ => "~",
+    }
+}
+
+/// Shortens big counts to something like `1.2M`.
+fn compact(n: u64) -> String {
+    match n {
+        0..1_000 => format!("{n}"),
+        1_000..1_000_000 => format!("{:.1}k", n as f32 / 1e3),
+        1_000_000..1_000_000_000 => format!("{:.1}M", n as f32 / 1e6),
+        _ => format!("{:.1}G", n as f32 / 1e9),
+    }
+}
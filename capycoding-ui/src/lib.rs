@@ -0,0 +1,355 @@
+//! The capycoder's screens, kept apart from the firmware so they can be drawn on any
+//! ratatui backend. The device draws them on its e-paper panel, tests on a `TestBackend`.
+
+#![no_std]
+
+extern crate alloc;
+
+use ratatui::{
+    Frame,
+    style::{Style, Stylize},
+    widgets::{Block, Paragraph, Wrap},
+};
+
+use alloc::format;
+use alloc::string::String;
+use heapless::HistoryBuf;
+
+use ble_types::{PERIPHERAL_NAME, ProvisioningStatus};
+
+use metrics::{ClaudeMetrics, CommitMetrics, MAX_ITEMS, PullRequest, WorkflowRun};
+
+mod dashboard;
+pub mod metrics;
+
+/// Columns and rows of text that fit the 296x128 panel with a 6x13 font.
+pub const COLUMNS: u16 = 49;
+pub const ROWS: u16 = 9;
+
+/// How many burn rate samples the dashboard sparkline shows, about its width in columns.
+const BURN_RATE_HISTORY: usize = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PageChange {
+    Next,
+    /// Back to the first page.
+    Home,
+}
+
+/// Charge left in the battery, as the firmware's battery monitor last measured it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Battery {
+    pub percent: u8,
+    /// Too flat to keep refreshing, the device asks to be charged instead.
+    pub low: bool,
+}
+
+/// Dashboard pages, in the order the button flips through them.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum Page {
+    #[default]
+    Overview,
+    Claude,
+    PullRequests,
+    Ci,
+    Device,
+}
+
+impl Page {
+    pub const COUNT: usize = 5;
+
+    pub fn next(self) -> Self {
+        match self {
+            Page::Overview => Page::Claude,
+            Page::Claude => Page::PullRequests,
+            Page::PullRequests => Page::Ci,
+            Page::Ci => Page::Device,
+            Page::Device => Page::Overview,
+        }
+    }
+}
+
+/// Everything the widget tree needs to draw a frame, kept up to date by the firmware's `ui_task`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UiState {
+    pub provisioned: bool,
+    /// Name given to the device when it was provisioned, may be empty.
+    pub device_name: heapless::String<30>,
+    /// Version of the firmware drawing this.
+    pub firmware: &'static str,
+    pub github_user: heapless::String<39>,
+    pub server_url: heapless::String<96>,
+    /// Why the config couldn't be loaded, shown until the device is provisioned again.
+    pub config_error: Option<heapless::String<48>>,
+    /// Set by a factory reset, cleared once the device is provisioned again.
+    pub reset: bool,
+    /// Passkey to show while a central is pairing.
+    pub passkey: Option<u32>,
+    pub claude: Option<ClaudeMetrics>,
+    pub pull_requests: heapless::Vec<PullRequest, MAX_ITEMS>,
+    pub workflow_runs: heapless::Vec<WorkflowRun, MAX_ITEMS>,
+    pub commits: Option<CommitMetrics>,
+    /// Burn rate in cents per hour, one sample per change.
+    pub burn_rate_history: HistoryBuf<u64, BURN_RATE_HISTORY>,
+    /// The server's certificate failed verification on the last attempt.
+    pub server_untrusted: bool,
+    /// `None` until the Wi-Fi task reported anything.
+    pub wifi: Option<ProvisioningStatus>,
+    pub ble_connected: bool,
+    pub page: Page,
+    /// `None` on USB powered builds without a battery monitor.
+    pub battery: Option<Battery>,
+    /// How much of a firmware update is downloaded, in percent, while one is running.
+    pub updating: Option<u8>,
+}
+
+impl UiState {
+    /// Forgets everything that belonged to whoever provisioned the device.
+    pub fn factory_reset(&mut self) {
+        self.reset = true;
+        // flash was wiped, whatever was wrong with it is gone
+        self.config_error = None;
+        self.claude = None;
+        self.pull_requests.clear();
+        self.workflow_runs.clear();
+        self.commits = None;
+        self.burn_rate_history.clear();
+        self.server_untrusted = false;
+        self.page = Page::Overview;
+    }
+
+    /// `None` means nobody has pushed Claude metrics to the server yet.
+    pub fn set_claude(&mut self, metrics: Option<ClaudeMetrics>) {
+        if let Some(metrics) = &metrics {
+            let cents = (metrics.burn_rate_per_hour * 100.0) as u64;
+            self.burn_rate_history.write(cents);
+        }
+        self.claude = metrics;
+    }
+
+    pub fn change_page(&mut self, change: PageChange) {
+        self.page = match change {
+            PageChange::Next => self.page.next(),
+            PageChange::Home => Page::Overview,
+        };
+    }
+
+    fn title(&self) -> &str {
+        if self.device_name.is_empty() {
+            PERIPHERAL_NAME
+        } else {
+            self.device_name.as_str()
+        }
+    }
+}
+
+/// The dashboard page on screen, `None` while a full screen message is shown instead.
+pub fn root_screen(state: &UiState) -> Option<Page> {
+    let dashboard = state.provisioned
+        && state.updating.is_none()
+        && state.passkey.is_none()
+        && !state.server_untrusted
+        && !battery_low(state);
+    dashboard.then_some(state.page)
+}
+
+fn battery_low(state: &UiState) -> bool {
+    state.battery.is_some_and(|battery| battery.low)
+}
+
+/// The root of the widget tree that draws everything else;
+pub fn root_draw(frame: &mut Frame, state: &UiState) {
+    if root_screen(state).is_some() {
+        dashboard::draw(frame, state, state.title());
+        return;
+    }
+
+    let text: String = if let Some(percent) = state.updating {
+        format!("Updating firmware, {percent}% done. Please keep me powered!")
+    } else {
+        match (state.passkey, &state.config_error) {
+            (Some(passkey), _) => format!("Pairing code: {passkey:06}"),
+            _ if battery_low(state) => "Battery low, please charge me!".into(),
+            _ if state.server_untrusted => {
+                "Server certificate rejected! Is this network safe?".into()
+            }
+            (None, Some(e)) => format!("{e}! Please reconnect to me."),
+            (None, None) if state.reset => "Reset".into(),
+            (None, None) => "Please connect to me!".into(),
+        }
+    };
+
+    let paragraph = Paragraph::new(text.dark_gray()).wrap(Wrap { trim: true });
+    let bordered_block = Block::bordered()
+        .border_style(Style::new().yellow())
+        .title(state.title());
+    frame.render_widget(paragraph.block(bordered_block), frame.area());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use heapless::Vec;
+    use insta::assert_snapshot;
+    use ratatui::{Terminal, backend::TestBackend};
+
+    fn render(state: &UiState) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(COLUMNS, ROWS)).unwrap();
+        terminal.draw(|f| root_draw(f, state)).unwrap();
+        format!("{}", terminal.backend())
+    }
+
+    fn pull_request(number: u32, title: &str, state: &str, merged: bool) -> PullRequest {
+        PullRequest {
+            number,
+            title: title.try_into().unwrap(),
+            state: state.try_into().unwrap(),
+            merged,
+        }
+    }
+
+    fn workflow_run(name: &str, status: &str, conclusion: &str) -> WorkflowRun {
+        WorkflowRun {
+            name: name.try_into().unwrap(),
+            status: status.try_into().unwrap(),
+            conclusion: conclusion.try_into().unwrap(),
+        }
+    }
+
+    /// Provisioned and online, but nothing fetched yet.
+    fn provisioned() -> UiState {
+        UiState {
+            provisioned: true,
+            firmware: "0.1.0",
+            device_name: "desk".try_into().unwrap(),
+            github_user: "capybara".try_into().unwrap(),
+            server_url: "https://capy.example.com/".try_into().unwrap(),
+            wifi: Some(ProvisioningStatus::ServerReachable),
+            ..Default::default()
+        }
+    }
+
+    fn with_metrics() -> UiState {
+        let mut state = provisioned();
+        for burn_rate_per_hour in [0.5, 1.25, 2.0, 1.75, 3.1, 2.4] {
+            state.set_claude(Some(ClaudeMetrics {
+                burn_rate_per_hour,
+                total_cost_usd: 12.34,
+                total_tokens: 1_234_567,
+                session_count: 3,
+            }));
+        }
+        state.pull_requests = Vec::from_slice(&[
+            pull_request(128, "Draw the dashboard on the e-paper", "open", false),
+            pull_request(127, "Verify the server certificate", "closed", true),
+            pull_request(125, "Remember more than one network", "closed", false),
+        ])
+        .unwrap();
+        state.workflow_runs = Vec::from_slice(&[
+            workflow_run("firmware", "in_progress", ""),
+            workflow_run("server", "completed", "success"),
+            workflow_run("app", "completed", "failure"),
+        ])
+        .unwrap();
+        state.commits = Some(CommitMetrics {
+            total: 42,
+            streak: 5,
+        });
+        state.ble_connected = true;
+        state.battery = Some(Battery {
+            percent: 64,
+            low: false,
+        });
+        state
+    }
+
+    #[test]
+    fn unprovisioned() {
+        assert_snapshot!(render(&UiState::default()));
+    }
+
+    #[test]
+    fn pairing() {
+        let state = UiState {
+            passkey: Some(4242),
+            ..provisioned()
+        };
+        assert_snapshot!(render(&state));
+    }
+
+    #[test]
+    fn config_error() {
+        let state = UiState {
+            config_error: Some("Flash I/O failed".try_into().unwrap()),
+            ..Default::default()
+        };
+        assert_snapshot!(render(&state));
+    }
+
+    #[test]
+    fn factory_reset() {
+        let mut state = with_metrics();
+        state.factory_reset();
+        state.provisioned = false;
+        assert_snapshot!(render(&state));
+    }
+
+    #[test]
+    fn server_untrusted() {
+        let state = UiState {
+            server_untrusted: true,
+            wifi: Some(ProvisioningStatus::ServerUntrusted),
+            ..with_metrics()
+        };
+        assert_snapshot!(render(&state));
+    }
+
+    #[test]
+    fn low_battery() {
+        let state = UiState {
+            battery: Some(Battery {
+                percent: 8,
+                low: true,
+            }),
+            ..with_metrics()
+        };
+        assert_snapshot!(render(&state));
+    }
+
+    #[test]
+    fn updating() {
+        let state = UiState {
+            updating: Some(42),
+            ..with_metrics()
+        };
+        assert_snapshot!(render(&state));
+    }
+
+    #[test]
+    fn overview_without_metrics() {
+        let state = UiState {
+            wifi: Some(ProvisioningStatus::GotIp),
+            ..provisioned()
+        };
+        assert_snapshot!(render(&state));
+    }
+
+    #[test]
+    fn every_page() {
+        let mut state = with_metrics();
+        for page in ["overview", "claude", "pull_requests", "ci", "device"] {
+            assert_snapshot!(page, render(&state));
+            state.change_page(PageChange::Next);
+        }
+        assert_eq!(state.page, Page::Overview);
+    }
+
+    #[test]
+    fn long_press_goes_home() {
+        let mut state = with_metrics();
+        state.change_page(PageChange::Next);
+        state.change_page(PageChange::Next);
+        state.change_page(PageChange::Home);
+        assert_eq!(state.page, Page::Overview);
+    }
+}
@@ -0,0 +1,81 @@
+//! Metrics as the CappyCoding server sends them.
+//!
+//! Responses are parsed straight into these fixed size structs. Long strings are cut short,
+//! the e-paper display couldn't show them in full anyway.
+
+use core::fmt;
+
+use heapless::String;
+use serde::Deserialize;
+use serde::de::{self, Deserializer};
+
+/// How many pull requests and workflow runs are fetched at a time.
+pub const MAX_ITEMS: usize = 5;
+
+/// Latest Claude usage pushed to the server by the desktop app, from `/metrics/claude`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ClaudeMetrics {
+    pub burn_rate_per_hour: f32,
+    pub total_cost_usd: f32,
+    pub total_tokens: u64,
+    pub session_count: u32,
+}
+
+/// One entry from `/metrics/prs`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct PullRequest {
+    pub number: u32,
+    #[serde(deserialize_with = "truncated")]
+    pub title: String<48>,
+    #[serde(deserialize_with = "truncated")]
+    pub state: String<8>,
+    #[serde(default)]
+    pub merged: bool,
+}
+
+/// One entry from `/metrics/workflows`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct WorkflowRun {
+    #[serde(deserialize_with = "truncated")]
+    pub name: String<32>,
+    #[serde(deserialize_with = "truncated")]
+    pub status: String<16>,
+    /// Empty until the run completes.
+    #[serde(deserialize_with = "truncated")]
+    pub conclusion: String<16>,
+}
+
+/// Commit count and streak from `/metrics/commits`.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct CommitMetrics {
+    pub total: u32,
+    /// Days in a row with commits, up to today. Older servers don't send it.
+    #[serde(default)]
+    pub streak: u32,
+}
+
+/// Deserializes a string, cutting it short on a char boundary instead of failing if it's too long.
+fn truncated<'de, D, const N: usize>(deserializer: D) -> Result<String<N>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    struct Visitor<const N: usize>;
+
+    impl<const N: usize> de::Visitor<'_> for Visitor<N> {
+        type Value = String<N>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "a string")
+        }
+
+        fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+            let mut end = value.len().min(N);
+            while !value.is_char_boundary(end) {
+                end -= 1;
+            }
+            String::try_from(&value[..end]).map_err(|_| E::custom("string too long"))
+        }
+    }
+
+    deserializer.deserialize_str(Visitor)
+}
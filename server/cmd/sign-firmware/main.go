@@ -0,0 +1,42 @@
+// Command sign-firmware signs firmware releases for the server's FIRMWARE_DIR.
+//
+//	sign-firmware -genkey                 prints a new signing key and its public half
+//	sign-firmware -key <file> <dir>       signs the capycoding-esp.bin in dir
+package main
+
+import (
+	"flag"
+	"fmt"
+	"log"
+	"os"
+
+	"cappycoding/server/internal/firmware"
+)
+
+func main() {
+	genKey := flag.Bool("genkey", false, "print a new signing key and its public half")
+	keyPath := flag.String("key", "", "file holding the signing key")
+	flag.Parse()
+
+	if *genKey {
+		seed, public, err := firmware.GenerateKey()
+		if err != nil {
+			log.Fatalf("failed to generate a key: %v", err)
+		}
+		fmt.Printf("signing key: %s\n", seed)
+		fmt.Printf("CAPYCODING_RELEASE_KEY=%s\n", public)
+		return
+	}
+
+	if *keyPath == "" || flag.NArg() != 1 {
+		fmt.Fprintln(os.Stderr, "usage: sign-firmware -genkey | sign-firmware -key <file> <firmware dir>")
+		os.Exit(2)
+	}
+	seed, err := os.ReadFile(*keyPath)
+	if err != nil {
+		log.Fatalf("failed to read the signing key: %v", err)
+	}
+	if err := firmware.Sign(flag.Arg(0), string(seed)); err != nil {
+		log.Fatalf("failed to sign the release: %v", err)
+	}
+}
@@ -0,0 +1,40 @@
+package firmware
+
+import (
+	"crypto/ed25519"
+	"crypto/rand"
+	"encoding/hex"
+	"fmt"
+	"os"
+	"path/filepath"
+	"strings"
+)
+
+// GenerateKey makes a new release signing key. The private half is returned as the
+// hex encoded seed Sign takes, the public half hex encoded for building into the
+// firmware as CAPYCODING_RELEASE_KEY.
+func GenerateKey() (seed string, public string, err error) {
+	publicKey, privateKey, err := ed25519.GenerateKey(rand.Reader)
+	if err != nil {
+		return "", "", err
+	}
+	return hex.EncodeToString(privateKey.Seed()), hex.EncodeToString(publicKey), nil
+}
+
+// Sign signs the SHA-256 of the release image in dir with the key whose hex encoded
+// seed is given, and writes the signature next to the image for Latest to publish.
+func Sign(dir, seed string) error {
+	rawSeed, err := hex.DecodeString(strings.TrimSpace(seed))
+	if err != nil || len(rawSeed) != ed25519.SeedSize {
+		return fmt.Errorf("signing key must be %d hex digits", 2*ed25519.SeedSize)
+	}
+	privateKey := ed25519.NewKeyFromSeed(rawSeed)
+
+	_, digest, err := hashImage(filepath.Join(dir, imageName))
+	if err != nil {
+		return err
+	}
+
+	signature := hex.EncodeToString(ed25519.Sign(privateKey, digest))
+	return os.WriteFile(filepath.Join(dir, signatureName), []byte(signature+"\n"), 0o644)
+}
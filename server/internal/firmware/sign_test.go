@@ -0,0 +1,65 @@
+package firmware
+
+import (
+	"crypto/ed25519"
+	"crypto/sha256"
+	"encoding/hex"
+	"os"
+	"path/filepath"
+	"testing"
+)
+
+func TestSign(t *testing.T) {
+	t.Parallel()
+
+	dir := t.TempDir()
+	image := []byte("abc")
+	if err := os.WriteFile(filepath.Join(dir, imageName), image, 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	if err := os.WriteFile(filepath.Join(dir, versionName), []byte("0.2.0"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+
+	seed, public, err := GenerateKey()
+	if err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	if err := Sign(dir, seed); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+
+	release, err := NewStore(dir).Latest()
+	if err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+
+	publicKey, err := hex.DecodeString(public)
+	if err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	signature, err := hex.DecodeString(release.Signature)
+	if err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	digest := sha256.Sum256(image)
+	if !ed25519.Verify(publicKey, digest[:], signature) {
+		t.Fatalf("signature doesn't verify: %s", release.Signature)
+	}
+}
+
+func TestSignWithInvalidKey(t *testing.T) {
+	t.Parallel()
+
+	dir := t.TempDir()
+	if err := os.WriteFile(filepath.Join(dir, imageName), []byte("abc"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+
+	if err := Sign(dir, "not a key"); err == nil {
+		t.Fatalf("expected an error for the invalid key")
+	}
+	if _, err := os.Stat(filepath.Join(dir, signatureName)); err == nil {
+		t.Fatalf("expected no signature to be written")
+	}
+}
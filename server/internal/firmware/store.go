@@ -0,0 +1,104 @@
+package firmware
+
+import (
+	"crypto/sha256"
+	"encoding/hex"
+	"errors"
+	"io"
+	"io/fs"
+	"os"
+	"path/filepath"
+	"strings"
+)
+
+// ErrNoRelease is returned while the firmware directory holds no release yet.
+var ErrNoRelease = errors.New("no firmware release available")
+
+const (
+	imageName     = "capycoding-esp.bin"
+	signatureName = imageName + ".sig"
+	versionName   = "version"
+)
+
+// Release describes the firmware image devices should be running.
+type Release struct {
+	Version string `json:"version"`
+	Size    int64  `json:"size"`
+	SHA256  string `json:"sha256"`
+
+	// Signature is the release key's Ed25519 signature of the SHA-256, hex encoded.
+	Signature string `json:"signature"`
+}
+
+// Store serves the firmware release kept in a directory: an app image named
+// capycoding-esp.bin next to its signature from Sign and a version file. All of them
+// are read on every request, so a release is published by replacing the files,
+// without restarting the server.
+type Store struct {
+	dir string
+}
+
+// NewStore builds a Store reading releases from dir.
+func NewStore(dir string) *Store {
+	return &Store{dir: dir}
+}
+
+// ImagePath is where the current release's image lives.
+func (s *Store) ImagePath() string {
+	return filepath.Join(s.dir, imageName)
+}
+
+// Latest describes the current release, hashing its image.
+func (s *Store) Latest() (Release, error) {
+	rawVersion, err := os.ReadFile(filepath.Join(s.dir, versionName))
+	if errors.Is(err, fs.ErrNotExist) {
+		return Release{}, ErrNoRelease
+	}
+	if err != nil {
+		return Release{}, err
+	}
+	version := strings.TrimSpace(string(rawVersion))
+	if version == "" {
+		return Release{}, errors.New("firmware version file is empty")
+	}
+
+	size, digest, err := hashImage(s.ImagePath())
+	if errors.Is(err, fs.ErrNotExist) {
+		return Release{}, ErrNoRelease
+	}
+	if err != nil {
+		return Release{}, err
+	}
+
+	// devices refuse unsigned images, so they aren't a release either
+	signature, err := os.ReadFile(filepath.Join(s.dir, signatureName))
+	if errors.Is(err, fs.ErrNotExist) {
+		return Release{}, ErrNoRelease
+	}
+	if err != nil {
+		return Release{}, err
+	}
+
+	return Release{
+		Version:   version,
+		Size:      size,
+		SHA256:    hex.EncodeToString(digest),
+		Signature: strings.TrimSpace(string(signature)),
+	}, nil
+}
+
+// hashImage returns the size and SHA-256 of the image at path.
+func hashImage(path string) (int64, []byte, error) {
+	image, err := os.Open(path)
+	if err != nil {
+		return 0, nil, err
+	}
+	defer image.Close()
+
+	hash := sha256.New()
+	size, err := io.Copy(hash, image)
+	if err != nil {
+		return 0, nil, err
+	}
+	return size, hash.Sum(nil), nil
+}
@@ -0,0 +1,79 @@
+package firmware
+
+import (
+	"errors"
+	"os"
+	"path/filepath"
+	"testing"
+)
+
+func TestStoreLatest(t *testing.T) {
+	t.Parallel()
+
+	dir := t.TempDir()
+	if err := os.WriteFile(filepath.Join(dir, imageName), []byte("abc"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	if err := os.WriteFile(filepath.Join(dir, signatureName), []byte("5ec0\n"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	if err := os.WriteFile(filepath.Join(dir, versionName), []byte("0.2.0\n"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+
+	release, err := NewStore(dir).Latest()
+	if err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+
+	want := Release{
+		Version:   "0.2.0",
+		Size:      3,
+		SHA256:    "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+		Signature: "5ec0",
+	}
+	if release != want {
+		t.Fatalf("unexpected release: %+v", release)
+	}
+}
+
+func TestStoreWithoutRelease(t *testing.T) {
+	t.Parallel()
+
+	dir := t.TempDir()
+	if _, err := NewStore(dir).Latest(); !errors.Is(err, ErrNoRelease) {
+		t.Fatalf("expected no release, got %v", err)
+	}
+
+	// a version without its image isn't a release either
+	if err := os.WriteFile(filepath.Join(dir, versionName), []byte("0.2.0"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	if _, err := NewStore(dir).Latest(); !errors.Is(err, ErrNoRelease) {
+		t.Fatalf("expected no release, got %v", err)
+	}
+
+	// nor is an image that wasn't signed
+	if err := os.WriteFile(filepath.Join(dir, imageName), []byte("abc"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	if _, err := NewStore(dir).Latest(); !errors.Is(err, ErrNoRelease) {
+		t.Fatalf("expected no release, got %v", err)
+	}
+}
+
+func TestStoreEmptyVersion(t *testing.T) {
+	t.Parallel()
+
+	dir := t.TempDir()
+	if err := os.WriteFile(filepath.Join(dir, imageName), []byte("abc"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	if err := os.WriteFile(filepath.Join(dir, versionName), []byte(" \n"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+
+	if _, err := NewStore(dir).Latest(); err == nil || errors.Is(err, ErrNoRelease) {
+		t.Fatalf("expected an error for the empty version, got %v", err)
+	}
+}
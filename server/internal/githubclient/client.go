@@ -181,6 +181,10 @@ type CommitMetrics struct {
 	ByAuthor map[string]int `json:"byAuthor"`
 	Since    *time.Time     `json:"since,omitempty"`
 	Until    *time.Time     `json:"until,omitempty"`
+
+	// Streak counts the days in a row, in UTC, with commits up to Until or today.
+	// Only UserCommitCount fills it in.
+	Streak int `json:"streak"`
 }
 
 // ErrInvalidRepository indicates that the repository reference is incomplete.
@@ -524,6 +528,7 @@ func (c *Client) UserCommitCount(ctx context.Context, username string, opts Comm
 		Since:    opts.Since,
 		Until:    opts.Until,
 	}
+	days := make(map[string]bool)
 
 	for {
 		results, resp, err := c.api.Search.Commits(ctx, strings.Join(query, " "), searchOpts)
@@ -540,6 +545,10 @@ func (c *Client) UserCommitCount(ctx context.Context, username string, opts Comm
 			}
 			metrics.ByAuthor[author]++
 			metrics.Total++
+
+			if date := commit.GetCommit().GetCommitter().GetDate(); !date.IsZero() {
+				days[date.UTC().Format(dayLayout)] = true
+			}
 		}
 
 		if resp.NextPage == 0 {
@@ -548,5 +557,30 @@ func (c *Client) UserCommitCount(ctx context.Context, username string, opts Comm
 		searchOpts.Page = resp.NextPage
 	}
 
+	end := time.Now()
+	if opts.Until != nil && opts.Until.Before(end) {
+		end = *opts.Until
+	}
+	metrics.Streak = commitStreak(days, end)
+
 	return metrics, nil
 }
+
+const dayLayout = "2006-01-02"
+
+// commitStreak counts the days in a row with commits, ending on the day of end.
+// A day without commits yet doesn't break the streak until it's over, so the
+// count then ends the day before.
+func commitStreak(days map[string]bool, end time.Time) int {
+	day := end.UTC()
+	if !days[day.Format(dayLayout)] {
+		day = day.AddDate(0, 0, -1)
+	}
+
+	streak := 0
+	for days[day.Format(dayLayout)] {
+		streak++
+		day = day.AddDate(0, 0, -1)
+	}
+	return streak
+}
@@ -271,6 +271,64 @@ func TestUserCommitCount(t *testing.T) {
 	}
 }
 
+func TestUserCommitCountStreak(t *testing.T) {
+	t.Parallel()
+
+	today := time.Now().UTC()
+	day := func(daysAgo int) string {
+		return today.AddDate(0, 0, -daysAgo).Format(time.RFC3339)
+	}
+
+	server := httptest.NewServer(http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+		w.Header().Set("Content-Type", "application/json")
+		w.WriteHeader(http.StatusOK)
+		_, _ = fmt.Fprintf(w, `{"items":[
+{"sha":"1","commit":{"committer":{"date":%q}}},
+{"sha":"2","commit":{"committer":{"date":%q}}},
+{"sha":"3","commit":{"committer":{"date":%q}}},
+{"sha":"4","commit":{"committer":{"date":%q}}}
+]}`, day(0), day(1), day(1), day(3))
+	}))
+	defer server.Close()
+
+	client := newTestClient(t, server)
+
+	metrics, err := client.UserCommitCount(context.Background(), "alice", CommitOptions{})
+	if err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+
+	if metrics.Streak != 2 {
+		t.Fatalf("expected a streak of 2, got %d", metrics.Streak)
+	}
+}
+
+func TestCommitStreak(t *testing.T) {
+	t.Parallel()
+
+	end := time.Date(2024, time.March, 10, 15, 0, 0, 0, time.UTC)
+	days := map[string]bool{
+		"2024-03-08": true,
+		"2024-03-09": true,
+		"2024-03-06": true,
+	}
+
+	// nothing yet today, the streak still runs up to yesterday
+	if got := commitStreak(days, end); got != 2 {
+		t.Fatalf("expected a streak of 2, got %d", got)
+	}
+
+	days["2024-03-10"] = true
+	if got := commitStreak(days, end); got != 3 {
+		t.Fatalf("expected a streak of 3, got %d", got)
+	}
+
+	// a whole day without commits ends it
+	if got := commitStreak(days, end.AddDate(0, 0, 2)); got != 0 {
+		t.Fatalf("expected no streak, got %d", got)
+	}
+}
+
 func newTestClient(t *testing.T, server *httptest.Server) *Client {
 	t.Helper()
 
@@ -2,6 +2,7 @@ package http
 
 import (
 	"context"
+	"crypto/subtle"
 	"errors"
 	"net/http"
 	"strconv"
@@ -9,8 +10,10 @@ import (
 	"time"
 
 	"github.com/labstack/echo/v4"
+	"github.com/labstack/echo/v4/middleware"
 
 	"cappycoding/server/internal/claude"
+	"cappycoding/server/internal/firmware"
 	"cappycoding/server/internal/githubclient"
 )
 
@@ -32,8 +35,8 @@ type claudeMetricsPayload struct {
 	Source              string  `json:"source"`
 }
 
-// RegisterRoutes wires the metrics endpoints on the provided Echo instance.
-func RegisterRoutes(e *echo.Echo, client *githubclient.Client, claudeStore *claude.Store) {
+// RegisterRoutes wires the metrics and firmware endpoints on the provided Echo instance.
+func RegisterRoutes(e *echo.Echo, client *githubclient.Client, claudeStore *claude.Store, firmwareStore *firmware.Store) {
 	e.GET("/metrics/prs", func(c echo.Context) error {
 		resolvedClient, err := resolveClient(c.Request().Context(), client, extractGitHubToken(c))
 		if err != nil {
@@ -129,6 +132,24 @@ func RegisterRoutes(e *echo.Echo, client *githubclient.Client, claudeStore *clau
 			return c.JSON(http.StatusOK, snapshot)
 		})
 	}
+
+	if firmwareStore != nil {
+		e.GET("/firmware/latest", func(c echo.Context) error {
+			release, err := firmwareStore.Latest()
+			if errors.Is(err, firmware.ErrNoRelease) {
+				return c.JSON(http.StatusNotFound, map[string]string{"error": err.Error()})
+			}
+			if err != nil {
+				return c.JSON(http.StatusInternalServerError, map[string]string{"error": err.Error()})
+			}
+			return c.JSON(http.StatusOK, release)
+		})
+
+		// devices download the image in pieces with Range requests, which File serves
+		e.GET("/firmware/image", func(c echo.Context) error {
+			return c.File(firmwareStore.ImagePath())
+		})
+	}
 }
 
 func queryParamInt(c echo.Context, key string, fallback int) int {
@@ -164,22 +185,44 @@ func respondError(c echo.Context, err error) error {
 	return c.JSON(status, map[string]string{"error": err.Error()})
 }
 
+// serverTokenKey marks requests RequireServerToken let through, their bearer token is the server token.
+const serverTokenKey = "serverToken"
+
+// RequireServerToken only lets through requests carrying token as `Authorization: Bearer <token>`,
+// the server token devices are provisioned with. Without it a bearer token is taken as a GitHub token.
+func RequireServerToken(token string) echo.MiddlewareFunc {
+	return middleware.KeyAuthWithConfig(middleware.KeyAuthConfig{
+		Validator: func(key string, c echo.Context) (bool, error) {
+			if subtle.ConstantTimeCompare([]byte(key), []byte(token)) != 1 {
+				return false, nil
+			}
+			c.Set(serverTokenKey, true)
+			return true, nil
+		},
+		// a missing token is as unauthorized as a wrong one
+		ErrorHandler: func(err error, c echo.Context) error {
+			return c.JSON(http.StatusUnauthorized, map[string]string{"error": "invalid server token"})
+		},
+	})
+}
+
 func extractGitHubToken(c echo.Context) string {
+	if token := c.Request().Header.Get("X-GitHub-Token"); token != "" {
+		return strings.TrimSpace(token)
+	}
+
 	auth := c.Request().Header.Get("Authorization")
 	if auth != "" {
 		lower := strings.ToLower(auth)
 		switch {
 		case strings.HasPrefix(lower, "token "):
 			return strings.TrimSpace(auth[6:])
-		case strings.HasPrefix(lower, "bearer "):
+		// a checked bearer token is the server token, never meant for GitHub
+		case strings.HasPrefix(lower, "bearer ") && c.Get(serverTokenKey) == nil:
 			return strings.TrimSpace(auth[7:])
 		}
 	}
 
-	if token := c.Request().Header.Get("X-GitHub-Token"); token != "" {
-		return strings.TrimSpace(token)
-	}
-
 	return ""
 }
 
@@ -5,6 +5,8 @@ import (
 	"errors"
 	nethttp "net/http"
 	"net/http/httptest"
+	"os"
+	"path/filepath"
 	"strings"
 	"testing"
 	"time"
@@ -13,6 +15,7 @@ import (
 	"github.com/labstack/echo/v4"
 
 	"cappycoding/server/internal/claude"
+	"cappycoding/server/internal/firmware"
 	"cappycoding/server/internal/githubclient"
 )
 
@@ -45,6 +48,14 @@ func TestExtractGitHubToken(t *testing.T) {
 			},
 			want: "xyz",
 		},
+		{
+			name: "custom header over authorization",
+			header: map[string]string{
+				"Authorization":  "Bearer proxy-token",
+				"X-GitHub-Token": "xyz",
+			},
+			want: "xyz",
+		},
 		{
 			name:   "missing headers",
 			header: map[string]string{},
@@ -142,7 +153,7 @@ func TestRegisterRoutesWithoutBaseClient(t *testing.T) {
 	defer func() { newGitHubClient = originalFactory }()
 
 	e := echo.New()
-	RegisterRoutes(e, nil, claude.NewStore(10))
+	RegisterRoutes(e, nil, claude.NewStore(10), nil)
 
 	req := httptest.NewRequest(nethttp.MethodGet, "/metrics/prs?user=alice", nil)
 	rec := httptest.NewRecorder()
@@ -166,6 +177,61 @@ func TestRegisterRoutesWithoutBaseClient(t *testing.T) {
 	}
 }
 
+func TestRegisterRoutesWithServerTokenOnly(t *testing.T) {
+	server := httptest.NewServer(nethttp.HandlerFunc(func(w nethttp.ResponseWriter, r *nethttp.Request) {
+		// the default token, not the server token the device sent
+		if got := r.Header.Get("Authorization"); got != "Bearer default-token" {
+			t.Fatalf("unexpected authorization header: %s", got)
+		}
+
+		w.Header().Set("Content-Type", "application/json")
+		_, _ = w.Write([]byte(`{"items":[{"number":1,"title":"Add feature","state":"open","html_url":"https://example.com/pr/1","updated_at":"2024-01-02T15:04:05Z","user":{"login":"alice"}}]}`))
+	}))
+	defer server.Close()
+
+	base, err := githubclient.NewClient(context.Background(),
+		githubclient.WithToken("default-token"),
+		githubclient.WithBaseURLs(server.URL, server.URL),
+		githubclient.WithHTTPClient(server.Client()),
+	)
+	if err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+
+	e := echo.New()
+	e.Use(RequireServerToken("server-token"))
+	RegisterRoutes(e, base, claude.NewStore(10), nil)
+
+	req := httptest.NewRequest(nethttp.MethodGet, "/metrics/prs?user=alice", nil)
+	rec := httptest.NewRecorder()
+	e.ServeHTTP(rec, req)
+
+	if rec.Code != nethttp.StatusUnauthorized {
+		t.Fatalf("expected unauthorized without the server token, got %d", rec.Code)
+	}
+
+	req = httptest.NewRequest(nethttp.MethodGet, "/metrics/prs?user=alice", nil)
+	req.Header.Set("Authorization", "Bearer wrong-token")
+	rec = httptest.NewRecorder()
+	e.ServeHTTP(rec, req)
+
+	if rec.Code != nethttp.StatusUnauthorized {
+		t.Fatalf("expected unauthorized with the wrong server token, got %d", rec.Code)
+	}
+
+	req = httptest.NewRequest(nethttp.MethodGet, "/metrics/prs?user=alice", nil)
+	req.Header.Set("Authorization", "Bearer server-token")
+	rec = httptest.NewRecorder()
+	e.ServeHTTP(rec, req)
+
+	if rec.Code != nethttp.StatusOK {
+		t.Fatalf("expected success, got %d with body %s", rec.Code, rec.Body.String())
+	}
+	if body := rec.Body.String(); !strings.Contains(body, "Add feature") {
+		t.Fatalf("unexpected body: %s", body)
+	}
+}
+
 func TestConvertClaudePayload(t *testing.T) {
 	t.Parallel()
 
@@ -201,7 +267,7 @@ func TestClaudeMetricsEndpoints(t *testing.T) {
 
 	store := claude.NewStore(5)
 	e := echo.New()
-	RegisterRoutes(e, nil, store)
+	RegisterRoutes(e, nil, store, nil)
 
 	req := httptest.NewRequest(nethttp.MethodGet, "/metrics/claude", nil)
 	rec := httptest.NewRecorder()
@@ -245,3 +311,52 @@ func TestClaudeMetricsEndpoints(t *testing.T) {
 		t.Fatalf("expected success fetching metrics, got %d", rec.Code)
 	}
 }
+
+func TestFirmwareEndpoints(t *testing.T) {
+	t.Parallel()
+
+	dir := t.TempDir()
+	e := echo.New()
+	RegisterRoutes(e, nil, nil, firmware.NewStore(dir))
+
+	req := httptest.NewRequest(nethttp.MethodGet, "/firmware/latest", nil)
+	rec := httptest.NewRecorder()
+	e.ServeHTTP(rec, req)
+
+	if rec.Code != nethttp.StatusNotFound {
+		t.Fatalf("expected 404 when no release available, got %d", rec.Code)
+	}
+
+	if err := os.WriteFile(filepath.Join(dir, "capycoding-esp.bin"), []byte("abcdef"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	if err := os.WriteFile(filepath.Join(dir, "capycoding-esp.bin.sig"), []byte("5ec0"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+	if err := os.WriteFile(filepath.Join(dir, "version"), []byte("0.2.0"), 0o644); err != nil {
+		t.Fatalf("unexpected error: %v", err)
+	}
+
+	req = httptest.NewRequest(nethttp.MethodGet, "/firmware/latest", nil)
+	rec = httptest.NewRecorder()
+	e.ServeHTTP(rec, req)
+
+	if rec.Code != nethttp.StatusOK {
+		t.Fatalf("expected success fetching the release, got %d", rec.Code)
+	}
+	if body := rec.Body.String(); !strings.Contains(body, `"version":"0.2.0"`) || !strings.Contains(body, `"size":6`) || !strings.Contains(body, `"signature":"5ec0"`) {
+		t.Fatalf("unexpected body: %s", body)
+	}
+
+	req = httptest.NewRequest(nethttp.MethodGet, "/firmware/image", nil)
+	req.Header.Set("Range", "bytes=2-3")
+	rec = httptest.NewRecorder()
+	e.ServeHTTP(rec, req)
+
+	if rec.Code != nethttp.StatusPartialContent {
+		t.Fatalf("expected a partial image, got %d", rec.Code)
+	}
+	if body := rec.Body.String(); body != "cd" {
+		t.Fatalf("unexpected body: %q", body)
+	}
+}
@@ -14,6 +14,7 @@ import (
 	"github.com/labstack/echo/v4/middleware"
 
 	"cappycoding/server/internal/claude"
+	"cappycoding/server/internal/firmware"
 	"cappycoding/server/internal/githubclient"
 	httpHandlers "cappycoding/server/internal/http"
 )
@@ -37,10 +38,20 @@ func main() {
 	e.HideBanner = true
 	e.Use(middleware.Logger())
 	e.Use(middleware.Recover())
+	if token := os.Getenv("SERVER_TOKEN"); token != "" {
+		log.Printf("requests must carry the server token")
+		e.Use(httpHandlers.RequireServerToken(token))
+	}
 
 	claudeStore := claude.NewStore(288)
 
-	httpHandlers.RegisterRoutes(e, client, claudeStore)
+	var firmwareStore *firmware.Store
+	if dir := os.Getenv("FIRMWARE_DIR"); dir != "" {
+		log.Printf("serving firmware updates from %s", dir)
+		firmwareStore = firmware.NewStore(dir)
+	}
+
+	httpHandlers.RegisterRoutes(e, client, claudeStore, firmwareStore)
 
 	srv := &http.Server{
 		Addr:         addr(),